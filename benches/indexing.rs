@@ -0,0 +1,114 @@
+//! Indexing throughput for different scheduler thread pool sizes.
+//!
+//! Documents are indexed on the scheduler's thread pool, but without the segment
+//! merges the scheduler runs after every indexed document, so that only indexing
+//! is timed.
+//!
+//! ```bash
+//! $ cargo bench --bench indexing
+//! ```
+
+use course_work_parallel_computing::channel::Channel;
+use course_work_parallel_computing::inverted_index::{IndexConfig, InvertedIndex, Metadata};
+use course_work_parallel_computing::threadpool::ThreadPool;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const DOCUMENTS: usize = 200;
+const WORDS_PER_DOCUMENT: usize = 2_000;
+const VOCABULARY_SIZE: u64 = 20_000;
+const THREAD_POOL_SIZES: [usize; 5] = [1, 2, 4, 8, 10];
+
+/// Writes documents of pseudo-random words to `directory`.
+fn create_documents(directory: &Path) -> Vec<String> {
+    let mut seed: u64 = 42;
+    let mut next_word = || {
+        // Linear congruential generator, so that every run indexes the same words
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        format!("word{}", (seed >> 33) % VOCABULARY_SIZE)
+    };
+
+    (0..DOCUMENTS)
+        .map(|i| {
+            let content: Vec<String> = (0..WORDS_PER_DOCUMENT).map(|_| next_word()).collect();
+            let path = directory.join(format!("document_{i}.txt"));
+            std::fs::write(&path, content.join(" ")).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+/// A new index with every document registered, but none indexed yet.
+fn registered_index(directory: &Path, documents: &[String]) -> (Arc<InvertedIndex>, Vec<u64>) {
+    let state_file = directory.join(format!("index_{}.bin", uuid::Uuid::new_v4()));
+    let index = InvertedIndex::with_config(IndexConfig {
+        state_file: state_file.to_string_lossy().into_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let ids = documents
+        .iter()
+        .map(|path| {
+            index
+                .register_document(path.clone(), Metadata::default())
+                .unwrap()
+        })
+        .collect();
+
+    (Arc::new(index), ids)
+}
+
+fn indexing(c: &mut Criterion) {
+    let directory: PathBuf =
+        std::env::temp_dir().join(format!("indexing_bench_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let documents = create_documents(&directory);
+
+    let mut group = c.benchmark_group("indexing");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(DOCUMENTS as u64));
+
+    for threads in THREAD_POOL_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched(
+                    || registered_index(&directory, &documents),
+                    |(index, ids)| {
+                        let thread_pool = ThreadPool::new(threads);
+                        let done = Arc::new(Channel::new());
+
+                        // Every document is queued up front, which keeps every worker busy
+                        for &id in &ids {
+                            let index = Arc::clone(&index);
+                            let done = Arc::clone(&done);
+                            thread_pool.execute(move || {
+                                index.index_document(id);
+                                done.send(());
+                            });
+                        }
+                        for _ in &ids {
+                            done.receive();
+                        }
+
+                        // Dropped outside of the measurement
+                        (index, thread_pool)
+                    },
+                    BatchSize::PerIteration,
+                );
+            },
+        );
+    }
+
+    group.finish();
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+criterion_group!(benches, indexing);
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+//! Query latency on an index of 10,000 documents, for single terms, `AND`, `NOT`
+//! and phrases. The documents are generated, unless `SEARCH_BENCH_DATA` points to a
+//! directory of text files, such as the load testing dataset, which are indexed
+//! instead. `SEARCH_BENCH_QUERIES` replaces the queries, separated by `;`.
+//!
+//! The heap memory held by the index once every document is indexed is printed
+//! before the queries are timed.
+//!
+//! ```bash
+//! $ cargo bench --bench search
+//! $ SEARCH_BENCH_DATA=/path/to/documents SEARCH_BENCH_QUERIES='data;data AND driven' \
+//!     cargo bench --bench search
+//! ```
+
+use course_work_parallel_computing::inverted_index::{IndexConfig, InvertedIndex, SearchOptions};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const DOCUMENTS: usize = 10_000;
+const WORDS_PER_DOCUMENT: usize = 300;
+const VOCABULARY_SIZE: u64 = 20_000;
+const QUERIES: [&str; 5] = [
+    "word1",
+    "word1 AND word2",
+    "word1 AND NOT word2",
+    "NOT word1",
+    "\"word1 word2\"",
+];
+
+/// Bytes currently allocated on the heap.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the bytes it hands out in [`ALLOCATED`].
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Writes documents of pseudo-random words to `directory`, where words with lower
+/// numbers are more frequent, as in natural language.
+fn create_documents(directory: &Path) -> Vec<String> {
+    let mut seed: u64 = 42;
+    let mut next = || {
+        // Linear congruential generator, so that every run indexes the same words
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        seed >> 33
+    };
+
+    (0..DOCUMENTS)
+        .map(|i| {
+            let content: Vec<String> = (0..WORDS_PER_DOCUMENT)
+                .map(|_| {
+                    let bound = next() % VOCABULARY_SIZE + 1;
+                    format!("word{}", next() % bound)
+                })
+                .collect();
+            let path = directory.join(format!("document_{i}.txt"));
+            std::fs::write(&path, content.join(" ")).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+        .collect()
+}
+
+/// Every file in `directory`.
+fn list_documents(directory: &Path) -> Vec<String> {
+    let mut documents: Vec<String> = std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    documents.sort();
+    documents
+}
+
+fn search(c: &mut Criterion) {
+    let directory: PathBuf =
+        std::env::temp_dir().join(format!("search_bench_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let documents = match std::env::var_os("SEARCH_BENCH_DATA") {
+        Some(data) => list_documents(Path::new(&data)),
+        None => create_documents(&directory),
+    };
+    let queries: Vec<String> = match std::env::var("SEARCH_BENCH_QUERIES") {
+        Ok(queries) => queries.split(';').map(str::to_string).collect(),
+        Err(_) => QUERIES.iter().map(|query| query.to_string()).collect(),
+    };
+
+    let allocated_before = ALLOCATED.load(Ordering::Relaxed);
+    let index = InvertedIndex::with_config(IndexConfig {
+        state_file: directory.join("index.bin").to_string_lossy().into_owned(),
+        ..Default::default()
+    })
+    .unwrap();
+    for path in &documents {
+        index.add_document(path.clone()).unwrap();
+    }
+    // Merges the segments of every shard into one
+    index.save().unwrap();
+
+    let index_size = ALLOCATED.load(Ordering::Relaxed) - allocated_before;
+    println!(
+        "Index of {} documents: {:.1} MiB on the heap",
+        documents.len(),
+        index_size as f64 / (1024.0 * 1024.0)
+    );
+
+    let options = SearchOptions {
+        limit: Some(10),
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("search");
+    for query in &queries {
+        let total = index
+            .search(query, &SearchOptions::default())
+            .unwrap()
+            .len();
+        println!("{query}: {total} of {} documents", documents.len());
+
+        group.bench_with_input(BenchmarkId::from_parameter(query), query, |b, query| {
+            b.iter(|| index.search(black_box(query), &options).unwrap());
+        });
+    }
+    group.finish();
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+criterion_group!(benches, search);
+criterion_main!(benches);
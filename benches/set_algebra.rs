@@ -0,0 +1,103 @@
+//! Boolean query set algebra on document ID sets, as `BTreeSet` and as roaring
+//! bitmaps, at the size of the 10,000 file load testing dataset. The heap size of
+//! both representations is printed before the timings.
+//!
+//! ```bash
+//! $ cargo bench --bench set_algebra
+//! ```
+
+use course_work_parallel_computing::inverted_index::DocumentSet;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const DOCUMENTS: u64 = 10_000;
+
+/// Counts the bytes currently allocated on the heap.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Heap bytes held by the value `build` returns.
+fn heap_size<T>(build: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let value = build();
+    (value, ALLOCATED.load(Ordering::SeqCst) - before)
+}
+
+/// IDs of the documents containing a term that appears in every `step`th document.
+fn ids(step: u64) -> impl Iterator<Item = u64> {
+    (0..DOCUMENTS).step_by(step as usize)
+}
+
+fn set_algebra(c: &mut Criterion) {
+    // Terms found in half, a tenth and a hundredth of the documents
+    let steps = [2, 10, 100];
+
+    let (trees, tree_size) = heap_size(|| {
+        steps
+            .map(|step| ids(step).collect::<BTreeSet<u64>>())
+            .to_vec()
+    });
+    let (bitmaps, bitmap_size) = heap_size(|| {
+        steps
+            .map(|step| ids(step).collect::<DocumentSet>())
+            .to_vec()
+    });
+    let all_trees: BTreeSet<u64> = (0..DOCUMENTS).collect();
+    let all_bitmaps: DocumentSet = (0..DOCUMENTS).collect();
+
+    println!("Heap size of the postings: BTreeSet {tree_size} B, roaring {bitmap_size} B");
+
+    let mut group = c.benchmark_group("and");
+    group.bench_function("btree", |b| {
+        b.iter(|| {
+            let result: BTreeSet<u64> = trees[0].intersection(&trees[1]).cloned().collect();
+            black_box(result.intersection(&trees[2]).count())
+        })
+    });
+    group.bench_function("roaring", |b| {
+        b.iter(|| black_box((&(&bitmaps[0] & &bitmaps[1]) & &bitmaps[2]).len()))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("or");
+    group.bench_function("btree", |b| {
+        b.iter(|| {
+            let result: BTreeSet<u64> = trees.iter().flatten().cloned().collect();
+            black_box(result.len())
+        })
+    });
+    group.bench_function("roaring", |b| {
+        b.iter(|| black_box((&(&bitmaps[0] | &bitmaps[1]) | &bitmaps[2]).len()))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("not");
+    group.bench_function("btree", |b| {
+        b.iter(|| black_box(all_trees.difference(&trees[0]).count()))
+    });
+    group.bench_function("roaring", |b| {
+        b.iter(|| black_box((&all_bitmaps - &bitmaps[0]).len()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, set_algebra);
+criterion_main!(benches);
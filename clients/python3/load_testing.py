@@ -1,7 +1,7 @@
 import os
 import time
 import threading
-from send import get_document_count, upload_file
+from send import Connection, get_document_count
 
 
 class LoadTester:
@@ -43,8 +43,9 @@ class LoadTester:
 
         desired_document_count = initial_document_count + len(self.files)
 
-        while get_document_count() < desired_document_count:
-            pass
+        with Connection() as connection:
+            while connection.get_document_count() < desired_document_count:
+                pass
 
         end = time.time()
         print(f"Оновлення індекса:\t\t{end - start} секунд")
@@ -56,8 +57,9 @@ class LoadTester:
         ]
 
     def upload_chunk(self, chunk):
-        for file_path in chunk:
-            response = upload_file(file_path)
+        with Connection() as connection:
+            for file_path in chunk:
+                document_id = connection.upload_file(file_path)
 
-            if response != "SUCCESS":
-                print(f"Failed to upload file '{file_path}'.")
+                if document_id is None:
+                    print(f"Failed to upload file '{file_path}'.")
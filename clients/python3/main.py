@@ -1,54 +1,190 @@
+import json
 import struct
 import os
 import click
 from load_testing import LoadTester
-from send import (
-    get_document_count,
-    send_command,
-    send_command_and_download_bytes,
-    upload_file,
-    MAX_STATUS_SIZE,
-)
+from send import Connection, file_payload, get_document_count, upload_file
 
 
 cli = click.Group()
 
 
+def parse_tags(ctx, param, tags):
+    """Turns `key=value` options into a dictionary."""
+    parsed = {}
+
+    for tag in tags:
+        key, separator, value = tag.partition("=")
+        if not separator:
+            raise click.BadParameter(f"Tag '{tag}' is not of the form key=value")
+        parsed[key] = value
+
+    return parsed
+
+
+def file_options(command):
+    """Options describing a file to upload and its metadata."""
+    command = click.option(
+        "--tag",
+        "tags",
+        multiple=True,
+        callback=parse_tags,
+        help="Tag stored with the document as key=value, can be repeated",
+    )(command)
+    command = click.option(
+        "--content-type",
+        type=str,
+        help="MIME type of the file, guessed from its extension by default",
+    )(command)
+    return click.option(
+        "--file-path", type=str, required=True, help="Path to the file to upload"
+    )(command)
+
+
+def highlight(snippet):
+    """Shows the <mark> highlights of a snippet in bold."""
+    return snippet.replace("<mark>", "\033[1m").replace("</mark>", "\033[0m")
+
+
 @cli.command()
-@click.option("--file-path", type=str, required=True, help="Path to the file to upload")
-def upload(file_path):
+@file_options
+@click.option("--wait", is_flag=True, help="Wait until the document is indexed")
+def upload(file_path, content_type, tags, wait):
     print(f"Uploading file: {file_path}")
 
-    response = upload_file(file_path)
+    document_id = upload_file(file_path, wait, content_type, tags)
 
-    if response == "SUCCESS":
-        print(f"File '{file_path}' uploaded successfully.")
+    if document_id is not None:
+        print(f"File '{file_path}' uploaded successfully. Document ID: {document_id}")
     else:
         print(f"Failed to upload file '{file_path}'.")
 
 
 @cli.command()
-@click.option("--term", type=str, required=True, help="Term to search for")
-def search(term):
-    payload = struct.pack(">Q", len(term))
+@click.option(
+    "--document-id", type=int, required=True, help="ID of the document to replace"
+)
+@file_options
+@click.option("--wait", is_flag=True, help="Wait until the new content is indexed")
+def update(document_id, file_path, content_type, tags, wait):
+    if not os.path.isfile(file_path):
+        print("File does not exist.")
+        return
+
+    payload = struct.pack(">Q?", document_id, wait)
+
+    payload += file_payload(file_path, content_type, tags)
+
+    print(f"Updating document ID {document_id} with file: {file_path}")
+
+    with Connection() as connection:
+        status, _ = connection.send_command("UPDATE", payload)
+
+    if status == "SUCCESS":
+        print(f"Document '{document_id}' updated successfully.")
+    else:
+        print(f"Document '{document_id}' not found.")
+
+
+@cli.command()
+@click.option(
+    "--term",
+    type=str,
+    required=True,
+    help='Query to search for, e.g. "rust AND (async OR NOT sync)"',
+)
+@click.option(
+    "--operator",
+    type=click.Choice(["or", "and"]),
+    default="or",
+    help="Operator used between terms without an explicit AND or OR",
+)
+@click.option(
+    "--limit",
+    type=int,
+    default=10,
+    help="Maximum number of results, 0 returns all of them",
+)
+@click.option(
+    "--offset",
+    type=int,
+    default=0,
+    help="Number of results to skip, for the following pages",
+)
+@click.option(
+    "--snippets",
+    is_flag=True,
+    help="Show an excerpt of each document with the matches highlighted",
+)
+def search(term, operator, limit, offset, snippets):
+    payload = struct.pack(
+        ">?QQ?Q", operator == "and", limit, offset, snippets, len(term.encode("utf-8"))
+    )
 
     payload += term.encode("utf-8")
 
     print(f"Searching for term: {term}")
 
-    response = send_command_and_download_bytes("SEARCH", payload)
+    with Connection() as connection:
+        status, response = connection.send_command("SEARCH", payload)
 
-    if b"SUCCESS" != response[:MAX_STATUS_SIZE]:
+    if status == "INVALID":
+        print(f"Invalid query: {response.decode('utf-8')}")
+        return
+
+    if status != "SUCCESS":
         print(f"Term '{term}' not found")
         return
 
-    response = response[MAX_STATUS_SIZE:].decode("utf-8")
+    response = json.loads(response)
+    hits, total = response["hits"], response["total"]
+
+    for misspelled, suggestions in response.get("suggestions", {}).items():
+        print(f"Did you mean {' or '.join(suggestions)} instead of '{misspelled}'?")
 
-    if response == "":
-        print(f"No documents found containing '{term}'")
+    if not hits:
+        if total == 0:
+            print(f"No documents found containing '{term}'")
+        else:
+            print(f"No more results, '{term}' matched {total} documents")
         return
 
-    print(f"Documents IDs containing '{term}': {response}")
+    print(f"Documents containing '{term}' ({offset + 1}-{offset + len(hits)} of {total}):")
+    for hit in hits:
+        print(f"  {hit['id']:>8}  score {hit['score']:.4f}")
+        if "snippet" in hit:
+            print(f"            {highlight(hit['snippet'])}")
+
+
+@cli.command()
+@click.option("--prefix", type=str, required=True, help="Beginning of the terms to complete")
+@click.option(
+    "--limit", type=int, default=10, help="Maximum number of terms to return"
+)
+def suggest(prefix, limit):
+    """Completes a prefix with the terms found in the most documents."""
+    payload = struct.pack(">QQ", limit, len(prefix.encode("utf-8")))
+
+    payload += prefix.encode("utf-8")
+
+    print(f"Completing prefix: {prefix}")
+
+    with Connection() as connection:
+        status, response = connection.send_command("SUGGST", payload)
+
+    if status != "SUCCESS":
+        print(f"Failed to complete '{prefix}'.")
+        return
+
+    suggestions = json.loads(response)["suggestions"]
+
+    if not suggestions:
+        print(f"No terms start with '{prefix}'")
+
+    for suggestion in suggestions:
+        print(
+            f"  {suggestion['term']:<24}  in {suggestion['document_frequency']} documents"
+        )
 
 
 @cli.command()
@@ -60,9 +196,10 @@ def delete(document_id):
 
     payload = struct.pack(">Q", document_id)
 
-    response = send_command("DELETE", payload)
+    with Connection() as connection:
+        status, _ = connection.send_command("DELETE", payload)
 
-    if response == "DELETED":
+    if status == "DELETED":
         print(f"Document '{document_id}' deleted successfully.")
     else:
         print(f"Document '{document_id}' not found or could not be deleted.")
@@ -79,21 +216,58 @@ def download(document_id):
     print(f"Downloading document ID: {document_id}")
     payload = struct.pack(">Q", document_id)
 
-    response = send_command_and_download_bytes("IMPORT", payload)
+    with Connection() as connection:
+        status, response = connection.send_command("IMPORT", payload)
 
-    if b"SUCCESS" not in response:
+    if status != "SUCCESS":
         print(f"Document '{document_id}' not found.")
         return
 
-    response = response[MAX_STATUS_SIZE:]
-    if len(response) == 0:
+    if len(response) < 8:
         print(f"Document '{document_id}' could not be downloaded.")
         return
 
-    with open(f"document_{document_id}.txt", "wb") as f:
-        f.write(response)
+    # The content is preceded by the document's metadata and its size
+    metadata_size = struct.unpack(">Q", response[:8])[0]
+    metadata = json.loads(response[8 : 8 + metadata_size])
+    content = response[8 + metadata_size :]
+
+    # Only the final component of the original name is used, so that a document
+    # is never written outside of the current directory
+    file_name = os.path.basename(metadata.get("filename") or "") or (
+        f"document_{document_id}.txt"
+    )
+
+    with open(file_name, "wb") as f:
+        f.write(content)
+
+    print(f"Document '{document_id}' downloaded successfully to '{file_name}'.")
+
+
+@cli.command()
+@click.option(
+    "--document-id", type=int, required=True, help="ID of the document to describe"
+)
+def info(document_id):
+    print(f"Requesting metadata of document ID: {document_id}")
+    payload = struct.pack(">Q", document_id)
+
+    with Connection() as connection:
+        status, response = connection.send_command("INFO  ", payload)
+
+    if status != "SUCCESS":
+        print(f"Document '{document_id}' not found.")
+        return
+
+    metadata = json.loads(response)
 
-    print(f"Document '{document_id}' downloaded successfully.")
+    print(f"Document {document_id}:")
+    print(f"  filename      {metadata['filename'] or 'unknown'}")
+    print(f"  content type  {metadata['content_type'] or 'unknown'}")
+    print(f"  size          {metadata['size']} bytes")
+    print(f"  uploaded at   {metadata['uploaded_at']} (Unix time)")
+    for key, value in metadata["tags"].items():
+        print(f"  tag           {key}={value}")
 
 
 @cli.command()
@@ -125,6 +299,84 @@ def load(num_threads: int, data_dir: str, max_dataset_size: int):
     print("Load testing completed")
 
 
+SORT_KEYS = ["id", "filename", "size", "uploaded"]
+
+
+@cli.command("list")
+@click.option(
+    "--sort",
+    type=click.Choice(SORT_KEYS),
+    default="id",
+    help="What to sort the documents by",
+)
+@click.option("--descending", is_flag=True, help="Sort in descending order")
+@click.option(
+    "--limit",
+    type=int,
+    default=20,
+    help="Maximum number of documents per page, 0 lists all of them",
+)
+@click.option("--cursor", type=str, default="", help="Cursor printed after the previous page")
+def list_documents(sort, descending, limit, cursor):
+    cursor = cursor.encode("utf-8")
+    payload = struct.pack(
+        ">B?QQ", SORT_KEYS.index(sort), descending, limit, len(cursor)
+    )
+
+    payload += cursor
+
+    print("Listing documents.")
+
+    with Connection() as connection:
+        status, response = connection.send_command("LIST  ", payload)
+
+    if status == "INVALID":
+        print(f"Invalid request: {response.decode('utf-8')}")
+        return
+
+    if status != "SUCCESS":
+        print("Failed to list documents.")
+        return
+
+    response = json.loads(response)
+
+    if not response["documents"]:
+        print("No documents found.")
+
+    for document in response["documents"]:
+        name = document["filename"] or document["path"]
+        state = "" if document["indexed"] else "  (indexing)"
+        print(
+            f"  {document['id']:>8}  {document['size']:>10} B"
+            f"  uploaded {document['uploaded_at']:>10}  {name}{state}"
+        )
+
+    if response["next_cursor"] is not None:
+        print(f'More documents with: --cursor "{response["next_cursor"]}"')
+
+
+@cli.command()
+@click.option("--wait", is_flag=True, help="Wait until all files are indexed")
+def rebuild(wait):
+    """Indexes the uploaded files that have no document, e.g. after a lost index."""
+    print("Rebuilding index from the uploads directory.")
+
+    with Connection() as connection:
+        status, response = connection.send_command("RBUILD", struct.pack(">?", wait))
+
+    if status != "SUCCESS":
+        print(f"Failed to rebuild index: {response.decode('utf-8')}")
+        return
+
+    response = json.loads(response)
+
+    def describe(ids):
+        return f"{len(ids)} ({', '.join(map(str, ids))})" if ids else "0"
+
+    print(f"Registered files without a document: {describe(response['added'])}")
+    print(f"Deleted documents without files: {describe(response['removed'])}")
+
+
 @cli.command()
 def status():
     documents_count = get_document_count()
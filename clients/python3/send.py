@@ -1,3 +1,5 @@
+import json
+import mimetypes
 import socket
 import struct
 import os
@@ -7,66 +9,102 @@ MAX_BUFFER_SIZE = 8192
 MAX_STATUS_SIZE = 7
 
 
-def send_command(command, payload=b""):
-    with socket.create_connection(SERVER_ADDRESS) as sock:
-        sock.sendall(command.encode("utf-8"))
-        sock.sendall(payload)
+class Connection:
+    """A connection to the server that can be reused for many commands."""
 
-        response = sock.recv(MAX_BUFFER_SIZE).decode("utf-8")
+    def __init__(self):
+        self.sock = socket.create_connection(SERVER_ADDRESS)
 
-        status = response[:MAX_STATUS_SIZE]
+    def __enter__(self):
+        return self
 
-        if status == "*ERROR*":
-            raise Exception("Server error, aborting")
+    def __exit__(self, *args):
+        self.close()
 
-        return response
+    def close(self):
+        try:
+            self.sock.sendall(b"QUIT  ")
+        finally:
+            self.sock.close()
+
+    def send_command(self, command, payload=b""):
+        """Sends a command and returns the framed response as (status, payload)."""
+        self.sock.sendall(command.encode("utf-8") + payload)
 
+        status = self.read_exact(MAX_STATUS_SIZE).decode("utf-8")
+        size = struct.unpack(">Q", self.read_exact(8))[0]
+        response = self.read_exact(size)
+
+        if status == "*ERROR*":
+            raise Exception(f"Server error: {response.decode('utf-8')}")
 
-def send_command_and_download_bytes(command, payload=b"") -> bytes:
-    with socket.create_connection(SERVER_ADDRESS) as sock:
-        sock.sendall(command.encode("utf-8") + payload)
+        return status, response
 
+    def read_exact(self, size) -> bytes:
         response = bytes()
 
-        while True:
-            data = sock.recv(MAX_BUFFER_SIZE)
+        while len(response) < size:
+            data = self.sock.recv(min(size - len(response), MAX_BUFFER_SIZE))
             if not data:
-                break
+                raise Exception("Connection closed before the response was complete")
             response += data
 
-        status = response[:MAX_STATUS_SIZE]
+        return response
 
-        if status == "*ERROR*":
-            raise Exception("Server error, aborting")
+    def upload_file(self, file_path, wait=False, content_type=None, tags=None):
+        if not os.path.isfile(file_path):
+            print("File does not exist.")
+            return
 
-        return response
+        payload = struct.pack(">?", wait)
 
+        payload += file_payload(file_path, content_type, tags)
 
-def upload_file(file_path):
-    if not os.path.isfile(file_path):
-        print("File does not exist.")
-        return
+        status, response = self.send_command("UPLOAD", payload)
 
-    with open(file_path, "rb") as f:
-        file_content = f.read()
+        if status != "SUCCESS":
+            return
 
-    payload = struct.pack(">Q", len(file_content))
+        return struct.unpack(">Q", response)[0]
 
-    payload += file_content
+    def get_document_count(self):
+        status, response = self.send_command("STATUS")
 
-    return send_command("UPLOAD", payload)
+        if status != "SUCCESS":
+            print("Server is not available.")
+            return
 
+        return struct.unpack(">Q", response)[0]
 
-def get_document_count():
-    response = send_command_and_download_bytes("STATUS")
 
-    if b"SUCCESS" not in response:
-        print("Server is not available.")
-        return
+def file_payload(file_path, content_type=None, tags=None):
+    """The metadata block, the file size and the file content of an upload."""
+    metadata = json.dumps(
+        {
+            "filename": os.path.basename(file_path),
+            "content_type": content_type
+            or mimetypes.guess_type(file_path)[0]
+            or "application/octet-stream",
+            "tags": tags or {},
+        }
+    ).encode("utf-8")
+
+    with open(file_path, "rb") as f:
+        file_content = f.read()
 
-    response = response[MAX_STATUS_SIZE:]
-    if len(response) != 8:
-        print("Server is not in a ready state.")
-        return
+    return (
+        struct.pack(">Q", len(metadata))
+        + metadata
+        + struct.pack(">Q", len(file_content))
+        + file_content
+    )
 
-    return struct.unpack(">Q", response)[0]
+
+def upload_file(file_path, wait=False, content_type=None, tags=None):
+    with Connection() as connection:
+        return connection.upload_file(file_path, wait, content_type, tags)
+
+
+def get_document_count():
+    with Connection() as connection:
+        return connection.get_document_count()
@@ -1,8 +1,8 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::{
     error::Error,
-    fs::{metadata, File},
-    io::{BufWriter, Read, Write},
+    fs::File,
+    io::{self, BufRead, BufWriter, Read, Write},
     net::{SocketAddr, TcpStream},
     path::Path,
     str,
@@ -12,179 +12,625 @@ const SERVER_ADDRESS: &str = "127.0.0.1:7878";
 const MAX_BUFFER_SIZE: usize = 8192;
 const MAX_STATUS_SIZE: usize = 7;
 
-fn send_command(command: &str, payload: Vec<u8>) -> Result<String, Box<dyn Error>> {
-    let server_address: SocketAddr = SERVER_ADDRESS.parse()?;
-    let mut stream = TcpStream::connect(server_address)?;
+/// A single connection to the server that is reused for every command.
+struct Connection {
+    stream: TcpStream,
+}
 
-    let mut data = Vec::new();
-    data.extend(command.as_bytes());
-    data.extend(payload);
+impl Connection {
+    fn open() -> Result<Self, Box<dyn Error>> {
+        let server_address: SocketAddr = SERVER_ADDRESS.parse()?;
+        let stream = TcpStream::connect(server_address)?;
 
-    for chunk in data.chunks(MAX_BUFFER_SIZE) {
-        stream.write_all(chunk)?;
+        Ok(Connection { stream })
     }
 
-    let mut buffer = [0; MAX_BUFFER_SIZE];
-    let bytes_read = stream.read(&mut buffer)?;
+    /// Sends a command and reads the framed response: status, payload size, payload.
+    fn send_command(
+        &mut self,
+        command: &str,
+        payload: Vec<u8>,
+    ) -> Result<(String, Vec<u8>), Box<dyn Error>> {
+        let mut data = Vec::new();
+        data.extend(command.as_bytes());
+        data.extend(payload);
+
+        for chunk in data.chunks(MAX_BUFFER_SIZE) {
+            self.stream.write_all(chunk)?;
+        }
 
-    let response = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
+        let mut buffer = [0; MAX_STATUS_SIZE];
+        self.stream.read_exact(&mut buffer)?;
+        let status = String::from_utf8_lossy(&buffer).to_string();
 
-    let status = &response[..MAX_STATUS_SIZE];
-    if status == "*ERROR*" {
-        return Err("Server error, aborting".into());
-    }
+        let payload = self.read_payload()?;
 
-    println!("Server response: {status}");
+        if status == "*ERROR*" {
+            let message = String::from_utf8_lossy(&payload);
+            return Err(format!("Server error: {message}").into());
+        }
 
-    Ok(response)
-}
+        println!("Server response: {status}");
 
-fn send_command_and_download_bytes(
-    command: &str,
-    payload: Vec<u8>,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let server_address: SocketAddr = SERVER_ADDRESS.parse()?;
-    let mut stream = TcpStream::connect(server_address)?;
+        Ok((status, payload))
+    }
 
-    stream.write_all(command.as_bytes())?;
-    stream.write_all(&payload)?;
+    fn read_payload(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buffer = [0; 8];
+        self.stream.read_exact(&mut buffer)?;
+        let size = u64::from_be_bytes(buffer);
 
-    let mut response = Vec::new();
-    let mut buffer = [0; MAX_BUFFER_SIZE];
+        let mut payload = Vec::new();
+        (&mut self.stream).take(size).read_to_end(&mut payload)?;
 
-    loop {
-        let bytes_read = stream.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+        if payload.len() as u64 != size {
+            return Err("Connection closed before the response was complete".into());
         }
-        response.extend_from_slice(&buffer[..bytes_read]);
+
+        Ok(payload)
     }
 
-    let status = &response[..MAX_STATUS_SIZE];
-    println!("Server response: {}", String::from_utf8_lossy(status).to_string());
-    if status == *b"*ERROR*" {
-        return Err("Server error, aborting".into());
+    fn quit(mut self) -> Result<(), Box<dyn Error>> {
+        self.stream.write_all(b"QUIT  ")?;
+
+        Ok(())
     }
 
+    fn upload(&mut self, file: &FileArgs, wait: bool) -> Result<(), Box<dyn Error>> {
+        let file_path = &file.file_path;
 
-    Ok(response)
-}
+        if !Path::new(file_path).is_file() {
+            println!("File does not exist.");
+            return Ok(());
+        }
+
+        let mut payload = Vec::new();
+        payload.push(wait as u8);
+        file.write_to(&mut payload)?;
 
-fn upload(file_path: &str) -> Result<(), Box<dyn Error>> {
-    if !Path::new(file_path).is_file() {
-        println!("File does not exist.");
-        return Ok(());
+        println!("Uploading file: {}", file_path);
+
+        let (status, response) = self.send_command("UPLOAD", payload)?;
+
+        if status != "SUCCESS" {
+            println!("Failed to upload file '{}'.", file_path);
+            return Ok(());
+        }
+
+        let document_id = u64::from_be_bytes(response.as_slice().try_into()?);
+        println!(
+            "File '{}' uploaded successfully. Document ID: {document_id}",
+            file_path
+        );
+
+        Ok(())
     }
 
-    let file_size = metadata(file_path)?.len();
-    let mut payload = Vec::new();
-    payload.extend_from_slice(&file_size.to_be_bytes());
+    fn update(
+        &mut self,
+        document_id: u64,
+        file: &FileArgs,
+        wait: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let file_path = &file.file_path;
+
+        if !Path::new(file_path).is_file() {
+            println!("File does not exist.");
+            return Ok(());
+        }
 
-    let mut file = File::open(file_path)?;
-    file.read_to_end(&mut payload)?;
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&document_id.to_be_bytes());
+        payload.push(wait as u8);
+        file.write_to(&mut payload)?;
 
-    println!("Uploading file: {}", file_path);
+        println!("Updating document ID {document_id} with file: {file_path}");
 
-    let response = send_command("UPLOAD", payload)?;
+        let (status, _) = self.send_command("UPDATE", payload)?;
 
-    if response == "SUCCESS" {
-        println!("File '{}' uploaded successfully.", file_path);
-    } else {
-        println!("Failed to upload file '{}'.", file_path);
+        if status == "SUCCESS" {
+            println!("Document '{document_id}' updated successfully.");
+        } else {
+            println!("Document '{document_id}' not found.");
+        }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    fn search(
+        &mut self,
+        term: &str,
+        operator: Operator,
+        limit: u64,
+        offset: u64,
+        snippets: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut payload = Vec::new();
+        payload.push(operator as u8);
+        payload.extend_from_slice(&limit.to_be_bytes());
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.push(snippets as u8);
+        payload.extend_from_slice(&(term.len() as u64).to_be_bytes());
+        payload.extend_from_slice(term.as_bytes());
+
+        println!("Searching for term: {}", term);
+
+        let (status, response) = self.send_command("SEARCH", payload)?;
+
+        if status == "INVALID" {
+            println!("Invalid query: {}", String::from_utf8_lossy(&response));
+            return Ok(());
+        }
+
+        if status != "SUCCESS" {
+            println!("Term '{}' not found", term);
+            return Ok(());
+        }
 
-fn search(term: &str) -> Result<(), Box<dyn Error>> {
-    let mut payload = Vec::new();
-    payload.extend_from_slice(&(term.len() as u64).to_be_bytes());
-    payload.extend_from_slice(term.as_bytes());
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        let hits = response["hits"].as_array().cloned().unwrap_or_default();
+        let total = response["total"].as_u64().unwrap_or_default();
+
+        for (misspelled, suggestions) in response["suggestions"].as_object().into_iter().flatten() {
+            let suggestions: Vec<&str> = suggestions
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|suggestion| suggestion.as_str())
+                .collect();
+            println!(
+                "Did you mean {} instead of '{misspelled}'?",
+                suggestions.join(" or ")
+            );
+        }
 
-    println!("Searching for term: {}", term);
+        if hits.is_empty() {
+            if total == 0 {
+                println!("No documents found containing '{}'", term);
+            } else {
+                println!("No more results, '{term}' matched {total} documents");
+            }
+            return Ok(());
+        }
 
-    let response = send_command_and_download_bytes("SEARCH", payload)?;
+        println!(
+            "Documents containing '{term}' ({}-{} of {total}):",
+            offset + 1,
+            offset + hits.len() as u64
+        );
+        for hit in hits {
+            println!(
+                "  {:>8}  score {:.4}",
+                hit["id"].as_u64().unwrap_or_default(),
+                hit["score"].as_f64().unwrap_or(0.0)
+            );
+            if let Some(snippet) = hit["snippet"].as_str() {
+                println!("            {}", highlight(snippet));
+            }
+        }
 
-    if &response[..MAX_STATUS_SIZE] != "SUCCESS".as_bytes() {
-        println!("Term '{}' not found", term);
-        return Ok(());
+        Ok(())
     }
 
-    let documents = String::from_utf8_lossy(&response[MAX_STATUS_SIZE..]);
-    if documents.is_empty() {
-        println!("No documents found containing '{}'", term);
-        return Ok(());
+    fn suggest(&mut self, prefix: &str, limit: u64) -> Result<(), Box<dyn Error>> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&limit.to_be_bytes());
+        payload.extend_from_slice(&(prefix.len() as u64).to_be_bytes());
+        payload.extend_from_slice(prefix.as_bytes());
+
+        println!("Completing prefix: {prefix}");
+
+        let (status, response) = self.send_command("SUGGST", payload)?;
+
+        if status != "SUCCESS" {
+            println!("Failed to complete '{prefix}'.");
+            return Ok(());
+        }
+
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        let suggestions = response["suggestions"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        if suggestions.is_empty() {
+            println!("No terms start with '{prefix}'");
+        }
+
+        for suggestion in suggestions {
+            println!(
+                "  {:<24}  in {} documents",
+                suggestion["term"].as_str().unwrap_or_default(),
+                suggestion["document_frequency"]
+                    .as_u64()
+                    .unwrap_or_default()
+            );
+        }
+
+        Ok(())
     }
 
-    println!("Documents containing '{term}': {documents}");
+    fn delete(&mut self, document_id: u64) -> Result<(), Box<dyn Error>> {
+        println!("Deleting document ID: {}", document_id);
 
-    Ok(())
-}
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&document_id.to_be_bytes());
+
+        let (status, _) = self.send_command("DELETE", payload)?;
+
+        if status == "DELETED" {
+            println!("Document '{}' deleted successfully.", document_id);
+        } else {
+            println!(
+                "Document '{}' not found or could not be deleted.",
+                document_id
+            );
+        }
+
+        Ok(())
+    }
+
+    fn download(&mut self, document_id: u64) -> Result<(), Box<dyn Error>> {
+        println!("Downloading document ID: {}", document_id);
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&document_id.to_be_bytes());
+
+        let (status, response) = self.send_command("IMPORT", payload)?;
+
+        if status != "SUCCESS" {
+            println!("Document '{}' not found.", document_id);
+            return Ok(());
+        }
 
-fn delete(document_id: u64) -> Result<(), Box<dyn Error>> {
-    println!("Deleting document ID: {}", document_id);
+        // The content is preceded by the document's metadata and its size
+        let (size, response) = response
+            .split_first_chunk::<8>()
+            .ok_or("Response is missing the metadata size")?;
+        let (metadata, file_content) = response
+            .split_at_checked(u64::from_be_bytes(*size) as usize)
+            .ok_or("Response is shorter than its metadata")?;
+        let metadata: serde_json::Value = serde_json::from_slice(metadata)?;
 
-    let mut payload = Vec::new();
-    payload.extend_from_slice(&document_id.to_be_bytes());
+        // Only the final component of the original name is used, so that a document
+        // is never written outside of the current directory
+        let file_name = metadata["filename"]
+            .as_str()
+            .and_then(|filename| Path::new(filename).file_name())
+            .map(|filename| filename.to_string_lossy().into_owned())
+            .unwrap_or_else(|| format!("document_{}.txt", document_id));
 
-    let response = send_command("DELETE", payload)?;
+        let mut file = BufWriter::new(File::create(&file_name)?);
+
+        file.write_all(file_content)?;
 
-    if response == "DELETED" {
-        println!("Document '{}' deleted successfully.", document_id);
-    } else {
         println!(
-            "Document '{}' not found or could not be deleted.",
+            "Document '{}' downloaded successfully to '{file_name}'.",
             document_id
         );
+
+        Ok(())
     }
 
-    Ok(())
-}
+    fn info(&mut self, document_id: u64) -> Result<(), Box<dyn Error>> {
+        println!("Requesting metadata of document ID: {}", document_id);
 
-fn download(document_id: u64) -> Result<(), Box<dyn Error>> {
-    println!("Downloading document ID: {}", document_id);
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&document_id.to_be_bytes());
 
-    let mut payload = Vec::new();
-    payload.extend_from_slice(&document_id.to_be_bytes());
+        let (status, response) = self.send_command("INFO  ", payload)?;
 
-    let response = match send_command_and_download_bytes("IMPORT", payload) {
-        Ok(response) => response,
-        Err(_) => {
+        if status != "SUCCESS" {
             println!("Document '{}' not found.", document_id);
             return Ok(());
         }
-    };
 
-    if !response.starts_with(b"SUCCESS") {
-        println!("Document '{}' not found.", document_id);
-        return Ok(());
+        let metadata: serde_json::Value = serde_json::from_slice(&response)?;
+        let text = |field: &str| metadata[field].as_str().unwrap_or("unknown").to_string();
+
+        println!("Document {document_id}:");
+        println!("  filename      {}", text("filename"));
+        println!("  content type  {}", text("content_type"));
+        println!("  size          {} bytes", metadata["size"]);
+        println!("  uploaded at   {} (Unix time)", metadata["uploaded_at"]);
+        if let Some(tags) = metadata["tags"].as_object() {
+            for (key, value) in tags {
+                println!(
+                    "  tag           {key}={}",
+                    value.as_str().unwrap_or_default()
+                );
+            }
+        }
+
+        Ok(())
     }
 
-    let file_content = &response[MAX_STATUS_SIZE..];
+    fn list(
+        &mut self,
+        sort: SortKey,
+        descending: bool,
+        limit: u64,
+        cursor: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut payload = Vec::new();
+        payload.push(sort as u8);
+        payload.push(descending as u8);
+        payload.extend_from_slice(&limit.to_be_bytes());
+        payload.extend_from_slice(&(cursor.len() as u64).to_be_bytes());
+        payload.extend_from_slice(cursor.as_bytes());
+
+        println!("Listing documents.");
+
+        let (status, response) = self.send_command("LIST  ", payload)?;
+
+        if status == "INVALID" {
+            println!("Invalid request: {}", String::from_utf8_lossy(&response));
+            return Ok(());
+        }
+
+        if status != "SUCCESS" {
+            println!("Failed to list documents.");
+            return Ok(());
+        }
+
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        let documents = response["documents"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        if documents.is_empty() {
+            println!("No documents found.");
+        }
 
-    let file_name = format!("document_{}.txt", document_id);
+        for document in documents {
+            println!(
+                "  {:>8}  {:>10} B  uploaded {:>10}  {}{}",
+                document["id"].as_u64().unwrap_or_default(),
+                document["size"].as_u64().unwrap_or_default(),
+                document["uploaded_at"].as_u64().unwrap_or_default(),
+                document["filename"]
+                    .as_str()
+                    .or(document["path"].as_str())
+                    .unwrap_or_default(),
+                if document["indexed"].as_bool().unwrap_or(true) {
+                    ""
+                } else {
+                    "  (indexing)"
+                }
+            );
+        }
 
-    let mut file = BufWriter::new(File::create(file_name)?);
+        if let Some(cursor) = response["next_cursor"].as_str() {
+            println!("More documents with: --cursor \"{cursor}\"");
+        }
 
-    file.write_all(file_content)?;
+        Ok(())
+    }
 
-    println!("Document '{}' downloaded successfully.", document_id);
+    fn status(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("Requesting server status.");
+        let (status, response) = self.send_command("STATUS", Vec::new())?;
 
-    Ok(())
+        if status != "SUCCESS" {
+            println!("Server is not available.");
+            return Ok(());
+        }
+
+        let document_count = u64::from_be_bytes(response.as_slice().try_into()?);
+        println!("Server is ready. Document count: {document_count}");
+        Ok(())
+    }
+
+    fn rebuild(&mut self, wait: bool) -> Result<(), Box<dyn Error>> {
+        println!("Rebuilding index from the uploads directory.");
+        let (status, response) = self.send_command("RBUILD", vec![wait as u8])?;
+
+        if status != "SUCCESS" {
+            println!(
+                "Failed to rebuild index: {}",
+                String::from_utf8_lossy(&response)
+            );
+            return Ok(());
+        }
+
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        let ids = |key: &str| -> String {
+            let ids: Vec<String> = response[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|id| id.as_u64())
+                .map(|id| id.to_string())
+                .collect();
+            match ids.len() {
+                0 => "0".to_string(),
+                count => format!("{count} ({})", ids.join(", ")),
+            }
+        };
+
+        println!("Registered files without a document: {}", ids("added"));
+        println!("Deleted documents without files: {}", ids("removed"));
+        Ok(())
+    }
+
+    fn run(&mut self, command: Commands) -> Result<(), Box<dyn Error>> {
+        match command {
+            Commands::Upload { file, wait } => self.upload(&file, wait),
+            Commands::Update {
+                document_id,
+                file,
+                wait,
+            } => self.update(document_id, &file, wait),
+            Commands::Search {
+                term,
+                operator,
+                limit,
+                offset,
+                snippets,
+            } => self.search(&term, operator, limit, offset, snippets),
+            Commands::Suggest { prefix, limit } => self.suggest(&prefix, limit),
+            Commands::Delete { document_id } => self.delete(document_id),
+            Commands::Download { document_id } => self.download(document_id),
+            Commands::Info { document_id } => self.info(document_id),
+            Commands::List {
+                sort,
+                descending,
+                limit,
+                cursor,
+            } => self.list(sort, descending, limit, &cursor),
+            Commands::Status => self.status(),
+            Commands::Rebuild { wait } => self.rebuild(wait),
+            Commands::Shell => self.shell(),
+        }
+    }
+
+    /// Reads commands from stdin, one per line, and runs them all over this connection.
+    fn shell(&mut self) -> Result<(), Box<dyn Error>> {
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            let words = split_arguments(&line);
+
+            if words.is_empty() {
+                continue;
+            }
+
+            match Cli::try_parse_from(std::iter::once("client".to_string()).chain(words)) {
+                Ok(Cli {
+                    command: Commands::Shell,
+                }) => println!("Already in shell mode."),
+                Ok(cli) => self.run(cli.command)?,
+                Err(e) => println!("{e}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a shell line on whitespace, keeping double-quoted arguments together.
+fn split_arguments(line: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut argument = String::new();
+    let mut in_quotes = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !argument.is_empty() {
+                    arguments.push(std::mem::take(&mut argument));
+                }
+            }
+            c => argument.push(c),
+        }
+    }
+
+    if !argument.is_empty() {
+        arguments.push(argument);
+    }
+
+    arguments
+}
+
+/// Parses a `key=value` tag.
+fn parse_tag(tag: &str) -> Result<(String, String), String> {
+    let (key, value) = tag
+        .split_once('=')
+        .ok_or_else(|| format!("Tag '{tag}' is not of the form key=value"))?;
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// MIME type of a file, guessed from its extension.
+fn guess_content_type(file_path: &str) -> &'static str {
+    let extension = Path::new(file_path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
 }
 
-fn status() -> Result<(), Box<dyn Error>> {
-    println!("Requesting server status.");
-    let response = send_command_and_download_bytes("STATUS", Vec::new())?;
+/// Shows the `<mark>` highlights of a snippet in bold.
+fn highlight(snippet: &str) -> String {
+    snippet
+        .replace("<mark>", "\x1b[1m")
+        .replace("</mark>", "\x1b[0m")
+}
+
+/// A file to upload together with its metadata.
+#[derive(Args, Debug)]
+struct FileArgs {
+    #[arg(short, long, help = "Path to the file to upload")]
+    file_path: String,
+    #[arg(
+        short,
+        long,
+        help = "MIME type of the file, guessed from its extension by default"
+    )]
+    content_type: Option<String>,
+    #[arg(
+        short = 't',
+        long = "tag",
+        value_parser = parse_tag,
+        help = "Tag stored with the document as key=value, can be repeated"
+    )]
+    tags: Vec<(String, String)>,
+}
 
-    if !response.starts_with(b"SUCCESS") {
-        println!("Server is not available.");
-        return Ok(());
+impl FileArgs {
+    /// Appends the metadata block, the file size and the file content.
+    fn write_to(&self, payload: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let filename = Path::new(&self.file_path)
+            .file_name()
+            .map(|filename| filename.to_string_lossy());
+        let content_type = self
+            .content_type
+            .as_deref()
+            .unwrap_or_else(|| guess_content_type(&self.file_path));
+        let tags: serde_json::Map<_, _> = self
+            .tags
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+
+        let metadata = serde_json::json!({
+            "filename": filename,
+            "content_type": content_type,
+            "tags": tags,
+        })
+        .to_string();
+
+        payload.extend_from_slice(&(metadata.len() as u64).to_be_bytes());
+        payload.extend_from_slice(metadata.as_bytes());
+
+        let content = std::fs::read(&self.file_path)?;
+        payload.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        payload.extend_from_slice(&content);
+
+        Ok(())
     }
+}
 
-    let document_count = u64::from_be_bytes(response[MAX_STATUS_SIZE..].try_into()?);
-    println!("Server is ready. Document count: {document_count}");
-    Ok(())
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Operator {
+    Or = 0,
+    And = 1,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SortKey {
+    Id = 0,
+    Filename = 1,
+    Size = 2,
+    Uploaded = 3,
 }
 
 #[derive(Parser, Debug)]
@@ -196,12 +642,64 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Upload {
-        #[arg(short, long, help = "Path to the file to upload")]
-        file_path: String,
+        #[command(flatten)]
+        file: FileArgs,
+        #[arg(short, long, help = "Wait until the document is indexed")]
+        wait: bool,
+    },
+    Update {
+        #[arg(short, long, help = "ID of the document to replace")]
+        document_id: u64,
+        #[command(flatten)]
+        file: FileArgs,
+        #[arg(short, long, help = "Wait until the new content is indexed")]
+        wait: bool,
     },
     Search {
-        #[arg(short, long, help = "Term to search for")]
+        #[arg(
+            short,
+            long,
+            help = "Query to search for, e.g. \"rust AND (async OR NOT sync)\""
+        )]
         term: String,
+        #[arg(
+            short,
+            long,
+            value_enum,
+            default_value_t = Operator::Or,
+            help = "Operator used between terms without an explicit AND or OR"
+        )]
+        operator: Operator,
+        #[arg(
+            short,
+            long,
+            default_value_t = 10,
+            help = "Maximum number of results, 0 returns all of them"
+        )]
+        limit: u64,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Number of results to skip, for the following pages"
+        )]
+        offset: u64,
+        #[arg(
+            long,
+            help = "Show an excerpt of each document with the matches highlighted"
+        )]
+        snippets: bool,
+    },
+    #[command(about = "Complete a prefix with the terms found in the most documents")]
+    Suggest {
+        #[arg(short, long, help = "Beginning of the terms to complete")]
+        prefix: String,
+        #[arg(
+            short,
+            long,
+            default_value_t = 10,
+            help = "Maximum number of terms to return"
+        )]
+        limit: u64,
     },
     Delete {
         #[arg(short, long, help = "ID of the document to delete")]
@@ -211,19 +709,54 @@ enum Commands {
         #[arg(short, long, help = "ID of the document to download")]
         document_id: u64,
     },
+    #[command(about = "Show the filename, content type, tags, size and upload time")]
+    Info {
+        #[arg(short, long, help = "ID of the document to describe")]
+        document_id: u64,
+    },
+    #[command(about = "List documents one page at a time")]
+    List {
+        #[arg(
+            short,
+            long,
+            value_enum,
+            default_value_t = SortKey::Id,
+            help = "What to sort the documents by"
+        )]
+        sort: SortKey,
+        #[arg(short, long, help = "Sort in descending order")]
+        descending: bool,
+        #[arg(
+            short,
+            long,
+            default_value_t = 20,
+            help = "Maximum number of documents per page, 0 lists all of them"
+        )]
+        limit: u64,
+        #[arg(
+            short,
+            long,
+            default_value = "",
+            help = "Cursor printed after the previous page"
+        )]
+        cursor: String,
+    },
     Status,
+    #[command(about = "Index the uploaded files that have no document, e.g. after a lost index")]
+    Rebuild {
+        #[arg(short, long, help = "Wait until all files are indexed")]
+        wait: bool,
+    },
+    #[command(about = "Run commands read from stdin over a single connection")]
+    Shell,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    match cli.command {
-        Commands::Upload { file_path } => upload(&file_path)?,
-        Commands::Search { term } => search(&term)?,
-        Commands::Delete { document_id } => delete(document_id)?,
-        Commands::Download { document_id } => download(document_id)?,
-        Commands::Status => status()?,
-    }
+    let mut connection = Connection::open()?;
+
+    connection.run(cli.command)?;
 
-    Ok(())
+    connection.quit()
 }
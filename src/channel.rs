@@ -1,9 +1,17 @@
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Condvar, Mutex};
 
 pub struct Channel<T> {
     queue: Mutex<VecDeque<T>>,
     items: Condvar,
+    closed: AtomicBool,
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T> Channel<T> {
@@ -11,6 +19,7 @@ impl<T> Channel<T> {
         Channel {
             queue: Mutex::new(VecDeque::new()),
             items: Condvar::new(),
+            closed: AtomicBool::new(false),
         }
     }
 
@@ -20,13 +29,25 @@ impl<T> Channel<T> {
         self.items.notify_one();
     }
 
+    /// Blocks until an item is available, or returns `None` once the channel is
+    /// closed and empty.
     pub fn receive(&self) -> Option<T> {
         let mut queue = self.queue.lock().unwrap();
         loop {
             if let Some(t) = queue.pop_front() {
                 return Some(t);
             }
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
             queue = self.items.wait(queue).unwrap();
         }
     }
+
+    /// Wakes up every receiver waiting on an empty channel.
+    pub fn close(&self) {
+        let _queue = self.queue.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        self.items.notify_all();
+    }
 }
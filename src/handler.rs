@@ -1,41 +1,104 @@
-use super::{inverted_index::InvertedIndex, UPLOADS_DIR};
+use super::inverted_index::{
+    InvertedIndex, ListOptions, Metadata, Operator, SearchOptions, SortKey, Upload,
+};
+use super::UPLOADS_DIR;
 use crate::scheduler::{Scheduler, Task};
 use log::{error, info};
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::str;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 const BUFFER_SIZE: usize = 8192;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const STATUS_SIZE: usize = 7;
+/// Number of completions returned when a `SUGGST` request does not ask for a limit.
+const DEFAULT_COMPLETIONS: usize = 10;
+/// Largest metadata block, query, prefix or cursor a client may send, so that a
+/// forged size cannot make the server allocate arbitrary amounts of memory.
+const MAX_PAYLOAD_SIZE: usize = 1 << 20;
+
+/// Every response frame starts with one of `SUCCESS`, `DELETED`, `MISSING`, `INVALID`
+/// or `*ERROR*`.
+type Status = [u8; STATUS_SIZE];
 
 enum Command {
     Upload,
+    Update,
     Search,
+    Suggest,
+    Rebuild,
     Delete,
     Import,
+    Info,
+    List,
     Status,
+    Quit,
     Unknown(Vec<u8>),
 }
 
 #[derive(Error, Debug)]
 enum HandlerError {
+    #[error("Unknown command received: {0:?}")]
+    UnknownCommand(Vec<u8>),
+
     #[error("Failed to create file: {0}")]
     FileNotCreated(std::io::Error),
 
+    #[error("Failed to open file: {0}")]
+    FailedToOpenFile(std::io::Error),
+
+    #[error("Failed to read upload flags")]
+    FailedToReadUploadFlags(std::io::Error),
+
     #[error("Failed to read file size")]
     FailedToReadSize(std::io::Error),
 
+    #[error("Payload of {0} bytes exceeds the limit of {MAX_PAYLOAD_SIZE} bytes")]
+    PayloadTooLarge(usize),
+
+    #[error("Failed to read document metadata")]
+    FailedToReadMetadata(std::io::Error),
+
+    #[error("Invalid document metadata")]
+    InvalidMetadata,
+
     #[error("Client disconnected unexpectedly")]
     ClientDisconnected(std::io::Error),
 
+    #[error("Failed to read search options")]
+    FailedToReadSearchOptions(std::io::Error),
+
     #[error("Failed to read search term")]
     FailedToReadSearchTerm(std::io::Error),
 
     #[error("Failed to decode search term")]
     FailedToDecodeSearchTerm(std::str::Utf8Error),
 
+    #[error("Failed to reconcile the uploads directory: {0}")]
+    FailedToReconcileUploads(std::io::Error),
+
+    #[error("Failed to read suggestion limit")]
+    FailedToReadSuggestionLimit(std::io::Error),
+
+    #[error("Failed to read prefix")]
+    FailedToReadPrefix(std::io::Error),
+
+    #[error("Failed to decode prefix")]
+    FailedToDecodePrefix(std::str::Utf8Error),
+
+    #[error("Failed to read list options")]
+    FailedToReadListOptions(std::io::Error),
+
+    #[error("Failed to read cursor")]
+    FailedToReadCursor(std::io::Error),
+
+    #[error("Failed to decode cursor")]
+    FailedToDecodeCursor(std::str::Utf8Error),
+
     #[error("Failed to read document ID")]
     FailedToReadDocumentId(std::io::Error),
 
@@ -47,10 +110,28 @@ enum HandlerError {
 
     #[error("Failed to write response")]
     FailedToWrite(std::io::Error),
+
+    #[error("Failed to write to the write-ahead log: {0}")]
+    FailedToLogOperation(std::io::Error),
 }
 
 type HandlerResult<T> = std::result::Result<T, HandlerError>;
 
+/// Answers a client the server has no room for with an `*ERROR*` frame, without
+/// reading its command, and closes the connection.
+pub fn reject_connection(mut stream: TcpStream) {
+    let message = b"Too many connections, try again later";
+
+    let mut frame = Vec::with_capacity(STATUS_SIZE + 8 + message.len());
+    frame.extend_from_slice(b"*ERROR*");
+    frame.extend_from_slice(&(message.len() as u64).to_be_bytes());
+    frame.extend_from_slice(message);
+
+    if let Err(e) = stream.write_all(&frame) {
+        error!("Failed to reject connection: {e:#?}");
+    }
+}
+
 pub struct Handler {
     stream: TcpStream,
     inverted_index: Arc<InvertedIndex>,
@@ -70,56 +151,194 @@ impl Handler {
         }
     }
 
+    /// Serves commands from the client until it sends `QUIT`, disconnects,
+    /// stays idle for longer than `IDLE_TIMEOUT` or a command fails.
     pub fn handle_client(&mut self) {
-        let mut stream = &self.stream;
-        let mut buffer = [0; 6];
-
-        if let Err(e) = stream.read_exact(&mut buffer) {
-            error!("Failed to read command: {e:#?}");
+        if let Err(e) = self.stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+            error!("Failed to set idle timeout: {e:#?}");
             return;
         }
 
-        let command = match &buffer {
-            b"UPLOAD" => Command::Upload,
-            b"SEARCH" => Command::Search,
-            b"DELETE" => Command::Delete,
-            b"IMPORT" => Command::Import,
-            b"STATUS" => Command::Status,
-            _ => Command::Unknown(buffer.to_vec()),
-        };
-
-        if let Err(e) = match command {
-            Command::Upload => self.handle_upload(),
-            Command::Search => self.handle_search(),
-            Command::Delete => self.handle_delete(),
-            Command::Import => self.handle_download(),
-            Command::Status => self.handle_status(),
-            Command::Unknown(command) => {
-                error!("Unknown command received: {command:?}");
+        loop {
+            let mut stream = &self.stream;
+            let mut buffer = [0; 6];
+
+            if let Err(e) = stream.read_exact(&mut buffer) {
+                match e.kind() {
+                    ErrorKind::UnexpectedEof => info!("Client closed the connection"),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        info!("Closing idle connection")
+                    }
+                    _ => error!("Failed to read command: {e:#?}"),
+                }
                 return;
             }
-        } {
-            if let Err(e) = self.write_response(b"*ERROR*") {
-                error!("Failed to write error response: {e:#?}");
+
+            let command = match &buffer {
+                b"UPLOAD" => Command::Upload,
+                b"UPDATE" => Command::Update,
+                b"SEARCH" => Command::Search,
+                b"SUGGST" => Command::Suggest,
+                b"RBUILD" => Command::Rebuild,
+                b"DELETE" => Command::Delete,
+                b"IMPORT" => Command::Import,
+                b"INFO  " => Command::Info,
+                b"LIST  " => Command::List,
+                b"STATUS" => Command::Status,
+                b"QUIT  " => Command::Quit,
+                _ => Command::Unknown(buffer.to_vec()),
+            };
+
+            // After a failure the rest of the request may still be unread,
+            // so the connection is closed instead of guessing where the next
+            // command starts.
+            if let Err(e) = match command {
+                Command::Upload => self.handle_upload(),
+                Command::Update => self.handle_update(),
+                Command::Search => self.handle_search(),
+                Command::Suggest => self.handle_suggest(),
+                Command::Rebuild => self.handle_rebuild(),
+                Command::Delete => self.handle_delete(),
+                Command::Import => self.handle_download(),
+                Command::Info => self.handle_info(),
+                Command::List => self.handle_list(),
+                Command::Status => self.handle_status(),
+                Command::Quit => {
+                    info!("Client requested to close the connection");
+                    return;
+                }
+                Command::Unknown(command) => Err(HandlerError::UnknownCommand(command)),
+            } {
+                let status = match e {
+                    HandlerError::PayloadTooLarge(_) => b"INVALID",
+                    _ => b"*ERROR*",
+                };
+                if let Err(e) = self.write_response(status, e.to_string().as_bytes()) {
+                    error!("Failed to write error response: {e:#?}");
+                }
+                error!("Error handling command: {e:#?}");
+                return;
             }
-            error!("Error handling command: {e:#?}");
         }
     }
 
     fn handle_upload(&self) -> HandlerResult<()> {
-        let mut stream = &self.stream;
+        let wait_for_indexing = self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadUploadFlags)?
+            != 0;
+
+        let metadata = self.read_metadata()?;
+        let (upload, size) = self.receive_file()?;
+        let upload_path = upload.path().to_string();
+
+        let document_id = match self
+            .inverted_index
+            .register_document(upload_path.clone(), metadata.received(size))
+        {
+            Ok(document_id) => document_id,
+            Err(e) => {
+                let _ = std::fs::remove_file(&upload_path);
+                return Err(HandlerError::FailedToLogOperation(e));
+            }
+        };
+        drop(upload);
+
+        let task = Task::AddDocument(document_id);
+
+        if wait_for_indexing {
+            self.scheduler.run_and_wait(task);
+        } else {
+            self.scheduler.run(task);
+        }
+
+        self.write_response(b"SUCCESS", &document_id.to_be_bytes())?;
+
+        info!("File upload complete, assigned document ID: {document_id}");
 
-        let file_size = self
+        Ok(())
+    }
+
+    /// Replaces the content of an existing document. The old content stays
+    /// searchable until the new one has been indexed.
+    fn handle_update(&self) -> HandlerResult<()> {
+        let document_id = self
             .read_usize()
-            .map_err(|e| HandlerError::FailedToReadSize(e))?;
+            .map_err(HandlerError::FailedToReadDocumentId)? as u64;
+
+        let wait_for_indexing = self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadUploadFlags)?
+            != 0;
+
+        info!("Updating document with ID: {document_id}");
+
+        let metadata = self.read_metadata()?;
+        let (upload, size) = self.receive_file()?;
+        let upload_path = upload.path().to_string();
+
+        match self.inverted_index.register_update(
+            document_id,
+            upload_path.clone(),
+            metadata.received(size),
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = std::fs::remove_file(&upload_path);
+                info!("Document to update not found");
+                return self.write_response(b"MISSING", &[]);
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&upload_path);
+                return Err(HandlerError::FailedToLogOperation(e));
+            }
+        }
+        drop(upload);
+
+        let task = Task::UpdateDocument(document_id);
+
+        if wait_for_indexing {
+            self.scheduler.run_and_wait(task);
+        } else {
+            self.scheduler.run(task);
+        }
+
+        self.write_response(b"SUCCESS", &[])?;
+
+        info!("Document update complete");
+
+        Ok(())
+    }
+
+    /// Reads the metadata block sent before a file: its size and a JSON object with
+    /// the filename, content type and tags.
+    fn read_metadata(&self) -> HandlerResult<Metadata> {
+        let buffer = self.read_payload(HandlerError::FailedToReadMetadata)?;
+
+        serde_json::from_slice(&buffer)
+            .ok()
+            .as_ref()
+            .and_then(Metadata::from_json)
+            .ok_or(HandlerError::InvalidMetadata)
+    }
+
+    /// Saves a file sent by the client to the uploads directory and returns the upload,
+    /// which keeps the file from being reconciled until it has been registered, and the
+    /// number of bytes received.
+    fn receive_file(&self) -> HandlerResult<(Upload<'_>, u64)> {
+        let mut stream = &self.stream;
+
+        let file_size = self.read_usize().map_err(HandlerError::FailedToReadSize)?;
 
-        let filename = format!("{}.txt", uuid::Uuid::new_v4().to_string());
+        let filename = format!("{}.txt", uuid::Uuid::new_v4());
 
         info!("Receiving file: {}", filename);
 
-        let upload_path = format!("{UPLOADS_DIR}/{filename}");
+        let upload = self
+            .inverted_index
+            .start_upload(format!("{UPLOADS_DIR}/{filename}"));
 
-        let mut file = File::create(&upload_path).map_err(|e| HandlerError::FileNotCreated(e))?;
+        let mut file = File::create(upload.path()).map_err(HandlerError::FileNotCreated)?;
 
         let buffer_size = std::cmp::min(file_size, BUFFER_SIZE);
         let mut buffer = vec![0; buffer_size];
@@ -127,65 +346,103 @@ impl Handler {
         let mut bytes_remaining = file_size;
 
         while bytes_remaining > 0 {
-            match stream.read(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        break;
-                    }
-
-                    file.write_all(&buffer[..bytes_read])
-                        .map_err(|e| HandlerError::FailedToWriteFile(e))?;
-
-                    bytes_remaining -= bytes_read;
-                }
+            let chunk = std::cmp::min(bytes_remaining, buffer_size);
+
+            let received = match stream.read(&mut buffer[..chunk]) {
+                // The client closed the connection before sending the whole file
+                Ok(0) => Err(HandlerError::ClientDisconnected(
+                    ErrorKind::UnexpectedEof.into(),
+                )),
+                Ok(bytes_read) => file
+                    .write_all(&buffer[..bytes_read])
+                    .map(|()| bytes_read)
+                    .map_err(HandlerError::FailedToWriteFile),
+                Err(e) => Err(HandlerError::ClientDisconnected(e)),
+            };
+
+            match received {
+                Ok(bytes_read) => bytes_remaining -= bytes_read,
                 Err(e) => {
-                    return Err(HandlerError::ClientDisconnected(e));
+                    // Removed before the upload guard is dropped, so that reconciliation
+                    // never registers a partial file
+                    drop(file);
+                    let _ = std::fs::remove_file(upload.path());
+                    return Err(e);
                 }
             }
         }
 
-        let task = Task::AddDocument(upload_path.clone());
-
-        self.scheduler.run(task);
-
-        self.write_response(b"SUCCESS")?;
-
-        info!("File upload complete");
-
-        Ok(())
+        Ok((upload, file_size as u64))
     }
 
     fn handle_search(&self) -> HandlerResult<()> {
-        let mut stream = &self.stream;
+        let default_operator = match self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadSearchOptions)?
+        {
+            0 => Operator::Or,
+            _ => Operator::And,
+        };
 
-        let search_term_size = self
+        let limit = match self
             .read_usize()
-            .map_err(|e| HandlerError::FailedToReadSize(e))?;
+            .map_err(HandlerError::FailedToReadSearchOptions)?
+        {
+            0 => None,
+            limit => Some(limit),
+        };
 
-        let mut buffer = vec![0; search_term_size];
+        let offset = self
+            .read_usize()
+            .map_err(HandlerError::FailedToReadSearchOptions)?;
 
-        stream
-            .read_exact(&mut buffer)
-            .map_err(|e| HandlerError::FailedToReadSearchTerm(e))?;
+        let snippets = self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadSearchOptions)?
+            != 0;
+
+        let buffer = self.read_payload(HandlerError::FailedToReadSearchTerm)?;
 
         let search_term =
-            str::from_utf8(&buffer).map_err(|e| HandlerError::FailedToDecodeSearchTerm(e))?;
+            str::from_utf8(&buffer).map_err(HandlerError::FailedToDecodeSearchTerm)?;
 
         info!("Searching for term: {search_term}");
 
-        let document_ids = self
-            .inverted_index
-            .search(search_term)
+        let options = SearchOptions {
+            default_operator,
+            offset,
+            limit,
+            snippets,
+        };
+
+        let results = match self.inverted_index.search_page(search_term, &options) {
+            Ok(results) => results,
+            Err(e) => {
+                info!("Invalid search query: {e}");
+                return self.write_response(b"INVALID", e.to_string().as_bytes());
+            }
+        };
+
+        let hits: Vec<_> = results
+            .hits
             .iter()
-            .map(|id| id.to_string())
-            .collect::<Vec<String>>()
-            .join(",");
+            .map(|hit| {
+                let mut json = serde_json::json!({ "id": hit.id, "score": hit.score });
+                if let Some(snippet) = &hit.snippet {
+                    json["snippet"] = snippet.as_str().into();
+                }
+                json
+            })
+            .collect();
 
-        let mut response = Vec::new();
-        response.extend_from_slice(b"SUCCESS");
-        response.extend_from_slice(&document_ids.as_bytes());
+        let response = serde_json::json!({
+            "hits": hits,
+            "offset": offset,
+            "total": results.total,
+            "suggestions": results.suggestions,
+        });
 
-        self.write_response(&response)?;
+        self.write_response(b"SUCCESS", response.to_string().as_bytes())?;
 
         info!("Search complete.");
 
@@ -195,19 +452,23 @@ impl Handler {
     fn handle_delete(&self) -> HandlerResult<()> {
         let document_id = self
             .read_usize()
-            .map_err(|e| HandlerError::FailedToReadDocumentId(e))?;
+            .map_err(HandlerError::FailedToReadDocumentId)?;
 
         info!("Deleting document with ID: {document_id}");
 
-        if !self.inverted_index.document_exists(document_id as u64) {
-            return self.write_response(b"MISSING");
-        }
+        let Some(paths) = self
+            .inverted_index
+            .unregister_document(document_id as u64)
+            .map_err(HandlerError::FailedToLogOperation)?
+        else {
+            return self.write_response(b"MISSING", &[]);
+        };
 
-        let task = Task::DeleteDocument(document_id as u64);
+        let task = Task::PurgeDocument(document_id as u64, paths);
 
         self.scheduler.run(task);
 
-        self.write_response(b"DELETED")?;
+        self.write_response(b"DELETED", &[])?;
 
         info!("Document deleted");
 
@@ -217,55 +478,244 @@ impl Handler {
     fn handle_download(&self) -> HandlerResult<()> {
         let document_id = self
             .read_usize()
-            .map_err(|e| HandlerError::FailedToReadDocumentId(e))?;
+            .map_err(HandlerError::FailedToReadDocumentId)?;
 
         info!("Downloading document with ID: {document_id}");
 
-        let document_path = match self.inverted_index.get_document_path(document_id as u64) {
-            Some(path) => path,
+        let document_id = document_id as u64;
+
+        let (document_path, metadata) = match self.inverted_index.get_document_file(document_id) {
+            Some(file) => file,
             None => {
-                error!("Requested document not found");
-                return Err(HandlerError::FileNotCreated(std::io::Error::from(
-                    std::io::ErrorKind::NotFound,
-                )));
+                info!("Requested document not found");
+                return self.write_response(b"MISSING", &[]);
             }
         };
 
-        self.write_response(b"SUCCESS")?;
+        let file = &mut File::open(document_path).map_err(HandlerError::FailedToOpenFile)?;
+
+        let file_size = file
+            .metadata()
+            .map_err(HandlerError::FailedToReadSize)?
+            .len();
+
+        // The metadata goes first, prefixed by its size, followed by the content
+        let metadata = metadata_json(document_id, &metadata);
 
-        let file = &mut File::open(document_path).map_err(|e| HandlerError::FileNotCreated(e))?;
+        self.write_response_header(b"SUCCESS", 8 + metadata.len() as u64 + file_size)?;
+        self.write_bytes(&(metadata.len() as u64).to_be_bytes())?;
+        self.write_bytes(&metadata)?;
+
+        let mut buffer = vec![0; BUFFER_SIZE];
 
         loop {
-            let buffer = file
-                .bytes()
-                .take(BUFFER_SIZE)
-                .collect::<Result<Vec<u8>, _>>()
-                .map_err(|e| HandlerError::FailedToWrite(e))?;
+            let bytes_read = file
+                .read(&mut buffer)
+                .map_err(HandlerError::FailedToWrite)?;
 
-            if buffer.is_empty() {
+            if bytes_read == 0 {
                 break;
             }
 
-            self.write_response(&buffer)?;
+            self.write_bytes(&buffer[..bytes_read])?;
         }
         Ok(())
     }
 
+    fn handle_info(&self) -> HandlerResult<()> {
+        let document_id = self
+            .read_usize()
+            .map_err(HandlerError::FailedToReadDocumentId)? as u64;
+
+        info!("Reading metadata of document with ID: {document_id}");
+
+        match self.inverted_index.get_document_metadata(document_id) {
+            Some(metadata) => {
+                self.write_response(b"SUCCESS", &metadata_json(document_id, &metadata))
+            }
+            None => {
+                info!("Requested document not found");
+                self.write_response(b"MISSING", &[])
+            }
+        }
+    }
+
+    fn handle_suggest(&self) -> HandlerResult<()> {
+        let limit = match self
+            .read_usize()
+            .map_err(HandlerError::FailedToReadSuggestionLimit)?
+        {
+            0 => DEFAULT_COMPLETIONS,
+            limit => limit,
+        };
+
+        let buffer = self.read_payload(HandlerError::FailedToReadPrefix)?;
+
+        let prefix = str::from_utf8(&buffer).map_err(HandlerError::FailedToDecodePrefix)?;
+
+        info!("Completing prefix: {prefix}");
+
+        let suggestions: Vec<_> = self
+            .inverted_index
+            .complete(prefix, limit)
+            .into_iter()
+            .map(|(term, document_frequency)| {
+                serde_json::json!({ "term": term, "document_frequency": document_frequency })
+            })
+            .collect();
+
+        let response = serde_json::json!({ "suggestions": suggestions });
+
+        self.write_response(b"SUCCESS", response.to_string().as_bytes())
+    }
+
+    /// Registers the files in the uploads directory that have no document, deletes the
+    /// documents whose files are gone and indexes the new documents in parallel.
+    fn handle_rebuild(&self) -> HandlerResult<()> {
+        let wait_for_indexing = self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadUploadFlags)?
+            != 0;
+
+        info!("Rebuilding index from {UPLOADS_DIR}");
+
+        let reconciled = self
+            .inverted_index
+            .reconcile_uploads(UPLOADS_DIR)
+            .map_err(HandlerError::FailedToReconcileUploads)?;
+
+        let tasks = reconciled
+            .added
+            .iter()
+            .map(|&document_id| Task::AddDocument(document_id))
+            .collect();
+
+        if wait_for_indexing {
+            self.scheduler.run_all_and_wait(tasks);
+        } else {
+            for task in tasks {
+                self.scheduler.run(task);
+            }
+        }
+
+        info!(
+            "Rebuild registered {} files and deleted {} documents",
+            reconciled.added.len(),
+            reconciled.removed.len()
+        );
+
+        let response = serde_json::json!({
+            "added": reconciled.added,
+            "removed": reconciled.removed,
+        });
+
+        self.write_response(b"SUCCESS", response.to_string().as_bytes())
+    }
+
+    fn handle_list(&self) -> HandlerResult<()> {
+        let sort = match self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadListOptions)?
+        {
+            1 => SortKey::Filename,
+            2 => SortKey::Size,
+            3 => SortKey::UploadedAt,
+            _ => SortKey::Id,
+        };
+
+        let descending = self
+            .read_u8()
+            .map_err(HandlerError::FailedToReadListOptions)?
+            != 0;
+
+        let limit = match self
+            .read_usize()
+            .map_err(HandlerError::FailedToReadListOptions)?
+        {
+            0 => None,
+            limit => Some(limit),
+        };
+
+        let buffer = self.read_payload(HandlerError::FailedToReadCursor)?;
+
+        let cursor = str::from_utf8(&buffer).map_err(HandlerError::FailedToDecodeCursor)?;
+
+        info!("Listing documents by {sort:?} after cursor {cursor:?}");
+
+        let options = ListOptions {
+            sort,
+            descending,
+            limit,
+            cursor: (!cursor.is_empty()).then(|| cursor.to_string()),
+        };
+
+        let page = match self.inverted_index.list_documents(&options) {
+            Ok(page) => page,
+            Err(e) => {
+                info!("Invalid list request: {e}");
+                return self.write_response(b"INVALID", e.to_string().as_bytes());
+            }
+        };
+
+        let documents: Vec<_> = page
+            .documents
+            .iter()
+            .map(|document| {
+                let mut json = document.metadata.to_json();
+                json["id"] = document.id.into();
+                json["path"] = document.path.clone().into();
+                json["indexed"] = document.indexed.into();
+                json
+            })
+            .collect();
+
+        let response = serde_json::json!({
+            "documents": documents,
+            "next_cursor": page.next_cursor,
+        });
+
+        self.write_response(b"SUCCESS", response.to_string().as_bytes())
+    }
+
     fn handle_status(&self) -> HandlerResult<()> {
         let documents = self.inverted_index.get_document_count();
 
-        self.write_response(b"SUCCESS")?;
-        self.write_response(&documents.to_be_bytes())?;
+        self.write_response(b"SUCCESS", &documents.to_be_bytes())?;
 
         Ok(())
     }
 
-    fn write_response(&self, response: &[u8]) -> HandlerResult<()> {
+    /// Writes a complete response frame: the status, the payload size and the payload.
+    fn write_response(&self, status: &Status, payload: &[u8]) -> HandlerResult<()> {
+        self.write_response_header(status, payload.len() as u64)?;
+        self.write_bytes(payload)
+    }
+
+    /// Writes the status and payload size of a frame whose payload is streamed afterwards.
+    fn write_response_header(&self, status: &Status, payload_size: u64) -> HandlerResult<()> {
+        let mut header = Vec::with_capacity(STATUS_SIZE + 8);
+        header.extend_from_slice(status);
+        header.extend_from_slice(&payload_size.to_be_bytes());
+
+        self.write_bytes(&header)
+    }
+
+    fn write_bytes(&self, bytes: &[u8]) -> HandlerResult<()> {
         let mut stream = &self.stream;
 
         stream
-            .write_all(response)
-            .map_err(|e| HandlerError::FailedToWriteResponse(e))
+            .write_all(bytes)
+            .map_err(HandlerError::FailedToWriteResponse)
+    }
+
+    fn read_u8(&self) -> std::io::Result<u8> {
+        let mut buffer = [0; 1];
+
+        let mut stream = &self.stream;
+
+        stream.read_exact(&mut buffer)?;
+
+        Ok(buffer[0])
     }
 
     fn read_usize(&self) -> std::io::Result<usize> {
@@ -277,4 +727,32 @@ impl Handler {
 
         Ok(usize::from_be_bytes(buffer))
     }
+
+    /// Reads a size followed by as many bytes, refusing sizes above
+    /// `MAX_PAYLOAD_SIZE` before allocating anything.
+    fn read_payload(
+        &self,
+        read_error: fn(std::io::Error) -> HandlerError,
+    ) -> HandlerResult<Vec<u8>> {
+        let size = self.read_usize().map_err(HandlerError::FailedToReadSize)?;
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(HandlerError::PayloadTooLarge(size));
+        }
+
+        let mut buffer = vec![0; size];
+
+        let mut stream = &self.stream;
+
+        stream.read_exact(&mut buffer).map_err(read_error)?;
+
+        Ok(buffer)
+    }
+}
+
+/// The metadata of a document as sent to clients, together with its ID.
+fn metadata_json(document_id: u64, metadata: &Metadata) -> Vec<u8> {
+    let mut metadata = metadata.to_json();
+    metadata["id"] = document_id.into();
+
+    metadata.to_string().into_bytes()
 }
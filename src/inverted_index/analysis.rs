@@ -0,0 +1,298 @@
+use super::tokenize;
+use rust_stemmers::{Algorithm, Stemmer};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String,
+    // Position of the token for phrase and proximity queries. Removed stop words leave
+    // a gap, while removed punctuation does not.
+    pub position: u32,
+    // Index of the token among all tokens of the text, including the filtered out ones
+    pub index: usize,
+    // Byte offset of the original token within the text
+    pub offset: usize,
+}
+
+/// A single step of the analysis chain. Returning `None` removes the token.
+pub trait TokenFilter: Send + Sync {
+    fn apply(&self, token: String) -> Option<String>;
+
+    /// Whether a token removed by this filter still takes up a position, so that
+    /// phrases do not match across it.
+    fn leaves_gap(&self) -> bool {
+        false
+    }
+}
+
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn apply(&self, token: String) -> Option<String> {
+        Some(token.to_lowercase())
+    }
+}
+
+/// Folds compatibility characters (e.g. ligatures, full-width letters) with NFKC.
+pub struct UnicodeNormalizationFilter;
+
+impl TokenFilter for UnicodeNormalizationFilter {
+    fn apply(&self, token: String) -> Option<String> {
+        Some(token.nfkc().collect())
+    }
+}
+
+/// Drops tokens without a single letter or digit, such as `!!` or `=====`.
+pub struct PunctuationFilter;
+
+impl TokenFilter for PunctuationFilter {
+    fn apply(&self, token: String) -> Option<String> {
+        token.chars().any(char::is_alphanumeric).then_some(token)
+    }
+}
+
+pub struct StopWordFilter {
+    stop_words: HashSet<&'static str>,
+}
+
+impl StopWordFilter {
+    pub fn english() -> Self {
+        StopWordFilter {
+            stop_words: ENGLISH_STOP_WORDS.iter().cloned().collect(),
+        }
+    }
+}
+
+impl TokenFilter for StopWordFilter {
+    fn apply(&self, token: String) -> Option<String> {
+        (!self.stop_words.contains(token.as_str())).then_some(token)
+    }
+
+    fn leaves_gap(&self) -> bool {
+        true
+    }
+}
+
+pub struct StemmerFilter {
+    stemmer: Stemmer,
+}
+
+impl StemmerFilter {
+    pub fn english() -> Self {
+        StemmerFilter {
+            stemmer: Stemmer::create(Algorithm::English),
+        }
+    }
+}
+
+impl TokenFilter for StemmerFilter {
+    fn apply(&self, token: String) -> Option<String> {
+        Some(self.stemmer.stem(&token).into_owned())
+    }
+}
+
+/// Named analysis chains an index can be built with. The kind is saved together
+/// with the index, because documents and queries must be analyzed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnalyzerKind {
+    /// Tokens are indexed exactly as written, as in indexes created before analyzers
+    Raw,
+    /// Normalized, lower-cased tokens without punctuation
+    #[default]
+    Standard,
+    /// Standard analysis plus English stop word removal and Snowball stemming
+    English,
+}
+
+impl AnalyzerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnalyzerKind::Raw => "raw",
+            AnalyzerKind::Standard => "standard",
+            AnalyzerKind::English => "english",
+        }
+    }
+}
+
+impl FromStr for AnalyzerKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "raw" => Ok(AnalyzerKind::Raw),
+            "standard" => Ok(AnalyzerKind::Standard),
+            "english" => Ok(AnalyzerKind::English),
+            _ => Err(format!("Unknown analyzer: {name}")),
+        }
+    }
+}
+
+/// Splits text into tokens and runs every token through a chain of filters.
+pub struct Analyzer {
+    kind: AnalyzerKind,
+    filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl Analyzer {
+    pub fn new(kind: AnalyzerKind) -> Self {
+        let mut filters: Vec<Box<dyn TokenFilter>> = Vec::new();
+
+        if kind != AnalyzerKind::Raw {
+            filters.push(Box::new(UnicodeNormalizationFilter));
+            filters.push(Box::new(LowercaseFilter));
+            filters.push(Box::new(PunctuationFilter));
+        }
+
+        if kind == AnalyzerKind::English {
+            filters.push(Box::new(StopWordFilter::english()));
+            filters.push(Box::new(StemmerFilter::english()));
+        }
+
+        Analyzer { kind, filters }
+    }
+
+    pub fn kind(&self) -> AnalyzerKind {
+        self.kind
+    }
+
+    /// Normalizes and lower-cases `text` like a token, without the filters that drop
+    /// or rewrite whole tokens. Used for wildcard patterns, which are matched against
+    /// the indexed terms as they are.
+    pub fn normalize(&self, text: &str) -> String {
+        if self.kind == AnalyzerKind::Raw {
+            return text.to_string();
+        }
+
+        text.nfkc().collect::<String>().to_lowercase()
+    }
+
+    pub fn analyze(&self, text: &str) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut position = 0;
+
+        'tokens: for (index, (offset, token)) in tokenize::tokenize_with_offsets(text).enumerate() {
+            let mut text = token.to_string();
+            for filter in &self.filters {
+                match filter.apply(text) {
+                    Some(filtered) => text = filtered,
+                    None => {
+                        if filter.leaves_gap() {
+                            position += 1;
+                        }
+                        continue 'tokens;
+                    }
+                }
+            }
+
+            tokens.push(Token {
+                text,
+                position,
+                index,
+                offset,
+            });
+            position += 1;
+        }
+
+        tokens
+    }
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Analyzer::new(AnalyzerKind::default())
+    }
+}
+
+impl fmt::Debug for Analyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Analyzer").field(&self.kind).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(analyzer: &Analyzer, text: &str) -> Vec<String> {
+        analyzer
+            .analyze(text)
+            .into_iter()
+            .map(|token| token.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_raw_keeps_tokens() {
+        let analyzer = Analyzer::new(AnalyzerKind::Raw);
+        assert_eq!(
+            texts(&analyzer, "Rust, rust!"),
+            vec!["Rust", ",", "rust", "!"]
+        );
+    }
+
+    #[test]
+    fn test_standard_folds_case_and_drops_punctuation() {
+        let analyzer = Analyzer::new(AnalyzerKind::Standard);
+        assert_eq!(
+            texts(&analyzer, "=====  Product Identification  ====="),
+            vec!["product", "identification"]
+        );
+        assert_eq!(texts(&analyzer, "ＲＵＳＴ ﬁle"), vec!["rust", "file"]);
+        assert_eq!(analyzer.normalize("ＲＵＳＴ?ﬁ*"), "rust?fi*");
+    }
+
+    #[test]
+    fn test_english_removes_stop_words_and_stems() {
+        let analyzer = Analyzer::new(AnalyzerKind::English);
+        assert_eq!(
+            texts(&analyzer, "The threads are running in the pools"),
+            vec!["thread", "run", "pool"]
+        );
+    }
+
+    #[test]
+    fn test_positions_and_offsets_survive_filtering() {
+        let analyzer = Analyzer::new(AnalyzerKind::English);
+        let tokens = analyzer.analyze("the thread, the pool");
+
+        // Only the stop words take up a position
+        assert_eq!(
+            tokens,
+            vec![
+                Token {
+                    text: "thread".to_string(),
+                    position: 1,
+                    index: 1,
+                    offset: 4
+                },
+                Token {
+                    text: "pool".to_string(),
+                    position: 3,
+                    index: 4,
+                    offset: 16
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kind_names() {
+        for kind in [
+            AnalyzerKind::Raw,
+            AnalyzerKind::Standard,
+            AnalyzerKind::English,
+        ] {
+            assert_eq!(kind.name().parse(), Ok(kind));
+        }
+        assert!("klingon".parse::<AnalyzerKind>().is_err());
+    }
+}
@@ -0,0 +1,568 @@
+//! Binary state file format.
+//!
+//! ```text
+//! header      magic "IIDX", format version (u32), last document ID (u64),
+//!             postings offset (u64), dictionary offset (u64), analyzer name
+//! documents   count, then ID delta, length, path and metadata of every document
+//! pending     count, then ID delta, path and metadata of every pending document
+//! postings    one block per term: document count, then for every document its
+//!             ID delta, the number of positions and the position deltas
+//! dictionary  count, then every term in sorted order with the offset of its
+//!             block (relative to the postings section) and its document count
+//! forward     count, then for every document its ID delta, the number of its
+//!             terms and the deltas of their indexes in the dictionary
+//! checksum    CRC32 of everything before it (u32)
+//! ```
+//!
+//! Metadata is the filename and the content type (empty if unknown), the tag count
+//! followed by every key and value, the upload timestamp and the size. Version 1
+//! files have no metadata, and files before version 3 have no forward section.
+//!
+//! Fixed-size integers are big-endian. Everything else is an unsigned LEB128
+//! varint, and strings are prefixed by their length in bytes.
+
+use super::storage::{LoadError, LoadResult, Snapshot, State};
+use super::{Document, Metadata, PendingDocument, Postings};
+use std::collections::{BTreeMap, HashMap};
+
+pub const MAGIC: &[u8; 4] = b"IIDX";
+pub const FORMAT_VERSION: u32 = 3;
+/// First version with a forward section.
+pub const FORWARD_VERSION: u32 = 3;
+
+const HEADER_SIZE: usize = 4 + 4 + 8 + 8 + 8;
+const CHECKSUM_SIZE: usize = 4;
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut documents: Vec<_> = snapshot.documents.iter().collect();
+    documents.sort_by_key(|(id, _)| **id);
+
+    let mut pending: Vec<_> = snapshot.pending.iter().collect();
+    pending.sort_by_key(|(id, _)| **id);
+
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    buffer.extend_from_slice(&snapshot.last_document_id.to_be_bytes());
+    // Section offsets are filled in once they are known
+    buffer.extend_from_slice(&[0; 16]);
+    write_string(&mut buffer, snapshot.analyzer.name());
+
+    write_varint(&mut buffer, documents.len() as u64);
+    let mut previous_id = 0;
+    for (&id, document) in documents {
+        write_varint(&mut buffer, id - previous_id);
+        write_varint(&mut buffer, document.length as u64);
+        write_string(&mut buffer, &document.path);
+        write_metadata(&mut buffer, &document.metadata);
+        previous_id = id;
+    }
+
+    write_varint(&mut buffer, pending.len() as u64);
+    let mut previous_id = 0;
+    for (&id, document) in pending {
+        write_varint(&mut buffer, id - previous_id);
+        write_string(&mut buffer, &document.path);
+        write_metadata(&mut buffer, &document.metadata);
+        previous_id = id;
+    }
+
+    let postings_offset = buffer.len();
+    let mut dictionary = Vec::new();
+    // ID -> Indexes of the document's terms in the dictionary
+    let mut forward: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+
+    for (term, postings) in snapshot.postings() {
+        for id in postings.documents() {
+            forward.entry(id).or_default().push(dictionary.len());
+        }
+        dictionary.push((term, buffer.len() - postings_offset, postings.len()));
+
+        write_varint(&mut buffer, postings.len() as u64);
+        let mut previous_id = 0;
+        for (id, positions) in postings.iter() {
+            write_varint(&mut buffer, id - previous_id);
+            write_varint(&mut buffer, positions.len() as u64);
+
+            let mut previous_position = 0;
+            for &position in positions {
+                write_varint(&mut buffer, (position - previous_position) as u64);
+                previous_position = position;
+            }
+
+            previous_id = id;
+        }
+    }
+
+    let dictionary_offset = buffer.len();
+
+    write_varint(&mut buffer, dictionary.len() as u64);
+    for (term, offset, document_frequency) in dictionary {
+        write_string(&mut buffer, term);
+        write_varint(&mut buffer, offset as u64);
+        write_varint(&mut buffer, document_frequency as u64);
+    }
+
+    write_varint(&mut buffer, forward.len() as u64);
+    let mut previous_id = 0;
+    for (id, terms) in forward {
+        write_varint(&mut buffer, id - previous_id);
+        write_varint(&mut buffer, terms.len() as u64);
+
+        let mut previous_term = 0;
+        for term in terms {
+            write_varint(&mut buffer, (term - previous_term) as u64);
+            previous_term = term;
+        }
+
+        previous_id = id;
+    }
+
+    buffer[16..24].copy_from_slice(&(postings_offset as u64).to_be_bytes());
+    buffer[24..32].copy_from_slice(&(dictionary_offset as u64).to_be_bytes());
+
+    let checksum = crc32fast::hash(&buffer);
+    buffer.extend_from_slice(&checksum.to_be_bytes());
+
+    buffer
+}
+
+pub fn decode(bytes: &[u8]) -> LoadResult<State> {
+    let content = verify(bytes)?;
+    let (mut state, sections) = decode_head(content)?;
+
+    let mut dictionary = Reader::new(content);
+    dictionary.position = sections.dictionary;
+
+    for _ in 0..dictionary.varint()? {
+        let entry = read_dictionary_entry(&mut dictionary)?;
+        let postings = decode_postings(content, &sections, entry.offset)?;
+        state.index.insert(entry.term.to_string(), postings);
+    }
+
+    Ok(state)
+}
+
+/// Checks the header and the checksum, and returns the content they cover.
+pub fn verify(bytes: &[u8]) -> LoadResult<&[u8]> {
+    if bytes.len() < HEADER_SIZE + CHECKSUM_SIZE || !bytes.starts_with(MAGIC) {
+        return Err(LoadError::InvalidField("header"));
+    }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    if crc32fast::hash(content).to_be_bytes() != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    Ok(content)
+}
+
+/// Everything before the checksum of a file that has already been verified.
+pub fn content(bytes: &[u8]) -> &[u8] {
+    &bytes[..bytes.len() - CHECKSUM_SIZE]
+}
+
+/// Where the postings and the dictionary start.
+#[derive(Debug, Clone, Copy)]
+pub struct Sections {
+    pub version: u32,
+    pub postings: usize,
+    pub dictionary: usize,
+}
+
+pub struct DictionaryEntry<'a> {
+    pub term: &'a str,
+    // Relative to the postings section
+    pub offset: usize,
+    pub document_frequency: usize,
+}
+
+/// Decodes everything before the postings, leaving the index of the state empty.
+pub fn decode_head(content: &[u8]) -> LoadResult<(State, Sections)> {
+    let mut reader = Reader::new(content);
+    reader.position = MAGIC.len();
+
+    let version = reader.u32()?;
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let metadata = |reader: &mut Reader| match version {
+        1 => Ok(Metadata::default()),
+        _ => read_metadata(reader),
+    };
+
+    let last_document_id = reader.u64()?;
+    let postings_offset = reader.u64()? as usize;
+    let dictionary_offset = reader.u64()? as usize;
+    let analyzer = reader
+        .string()?
+        .parse()
+        .map_err(|_| LoadError::InvalidField("analyzer"))?;
+
+    let mut documents = HashMap::new();
+    let mut id: u64 = 0;
+    for _ in 0..reader.varint()? {
+        id = id.wrapping_add(reader.varint()?);
+        let length = reader.varint()? as u32;
+        let path = reader.string()?.to_string();
+        documents.insert(
+            id,
+            Document {
+                path,
+                length,
+                generation: 0,
+                metadata: metadata(&mut reader)?,
+            },
+        );
+    }
+
+    let mut pending = HashMap::new();
+    let mut id: u64 = 0;
+    for _ in 0..reader.varint()? {
+        id = id.wrapping_add(reader.varint()?);
+        let path = reader.string()?.to_string();
+        pending.insert(
+            id,
+            PendingDocument {
+                path,
+                metadata: metadata(&mut reader)?,
+            },
+        );
+    }
+
+    if reader.position != postings_offset || dictionary_offset > content.len() {
+        return Err(LoadError::InvalidField("postings offset"));
+    }
+
+    let state = State {
+        documents,
+        pending,
+        last_document_id,
+        analyzer,
+        ..Default::default()
+    };
+    let sections = Sections {
+        version,
+        postings: postings_offset,
+        dictionary: dictionary_offset,
+    };
+
+    Ok((state, sections))
+}
+
+pub fn read_dictionary_entry<'a>(reader: &mut Reader<'a>) -> LoadResult<DictionaryEntry<'a>> {
+    let term = reader.string()?;
+    let offset = reader.varint()? as usize;
+    let document_frequency = reader.varint()? as usize;
+
+    Ok(DictionaryEntry {
+        term,
+        offset,
+        document_frequency,
+    })
+}
+
+/// Reads the terms of a document from the forward section, as indexes into the
+/// dictionary in ascending order. Fails if an index is not below `terms`.
+pub fn read_document_terms(reader: &mut Reader, terms: usize) -> LoadResult<Vec<usize>> {
+    let count = reader.varint()? as usize;
+    let mut indexes = Vec::with_capacity(count.min(reader.remaining()));
+    let mut index: usize = 0;
+
+    for _ in 0..count {
+        index = index.saturating_add(reader.varint()? as usize);
+        if index >= terms {
+            return Err(LoadError::InvalidField("forward index"));
+        }
+        indexes.push(index);
+    }
+
+    Ok(indexes)
+}
+
+/// Decodes the postings block at `offset` within the postings section.
+pub fn decode_postings(content: &[u8], sections: &Sections, offset: usize) -> LoadResult<Postings> {
+    let mut reader = Reader::new(&content[..sections.dictionary]);
+    reader.position = sections.postings.saturating_add(offset);
+
+    let mut postings = Postings::new();
+    let mut id: u64 = 0;
+
+    for _ in 0..reader.varint()? {
+        id = id.wrapping_add(reader.varint()?);
+
+        let count = reader.varint()? as usize;
+        let mut positions = Vec::with_capacity(count.min(reader.remaining()));
+        let mut position: u32 = 0;
+        for _ in 0..count {
+            position = position.wrapping_add(reader.varint()? as u32);
+            positions.push(position);
+        }
+
+        if !postings.push(id, &positions) {
+            return Err(LoadError::InvalidField("postings"));
+        }
+    }
+
+    Ok(postings)
+}
+
+fn write_metadata(buffer: &mut Vec<u8>, metadata: &Metadata) {
+    write_string(buffer, metadata.filename.as_deref().unwrap_or_default());
+    write_string(buffer, metadata.content_type.as_deref().unwrap_or_default());
+
+    write_varint(buffer, metadata.tags.len() as u64);
+    for (key, value) in &metadata.tags {
+        write_string(buffer, key);
+        write_string(buffer, value);
+    }
+
+    write_varint(buffer, metadata.uploaded_at);
+    write_varint(buffer, metadata.size);
+}
+
+fn read_metadata(reader: &mut Reader) -> LoadResult<Metadata> {
+    let optional = |value: &str| (!value.is_empty()).then(|| value.to_string());
+
+    let filename = optional(reader.string()?);
+    let content_type = optional(reader.string()?);
+
+    let mut tags = BTreeMap::new();
+    for _ in 0..reader.varint()? {
+        let key = reader.string()?.to_string();
+        tags.insert(key, reader.string()?.to_string());
+    }
+
+    Ok(Metadata {
+        filename,
+        content_type,
+        tags,
+        uploaded_at: reader.varint()?,
+        size: reader.varint()?,
+    })
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.position)
+    }
+
+    fn take(&mut self, size: usize) -> LoadResult<&'a [u8]> {
+        if size > self.remaining() {
+            return Err(LoadError::InvalidField("length"));
+        }
+
+        let bytes = &self.bytes[self.position..self.position + size];
+        self.position += size;
+
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> LoadResult<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> LoadResult<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn varint(&mut self) -> LoadResult<u64> {
+        let mut value = 0;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(LoadError::InvalidField("varint"))
+    }
+
+    fn string(&mut self) -> LoadResult<&'a str> {
+        let size = self.varint()? as usize;
+
+        std::str::from_utf8(self.take(size)?).map_err(|_| LoadError::InvalidField("string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::AnalyzerKind;
+    use super::*;
+
+    fn snapshot_round_trip(index: HashMap<String, Postings>) -> (Vec<u8>, State) {
+        let documents = HashMap::from([
+            (
+                3,
+                Document {
+                    path: "uploads/a.txt".to_string(),
+                    length: 4,
+                    generation: 0,
+                    metadata: Metadata {
+                        filename: Some("a.txt".to_string()),
+                        content_type: Some("text/plain".to_string()),
+                        tags: BTreeMap::from([("lang".to_string(), "en".to_string())]),
+                        uploaded_at: 1_700_000_000,
+                        size: 20,
+                    },
+                },
+            ),
+            (
+                300,
+                Document {
+                    path: "uploads/b.txt".to_string(),
+                    length: 1,
+                    generation: 0,
+                    metadata: Metadata::default(),
+                },
+            ),
+        ]);
+        let pending = HashMap::from([(
+            301,
+            PendingDocument {
+                path: "uploads/c.txt".to_string(),
+                metadata: Metadata {
+                    filename: Some("c.txt".to_string()),
+                    ..Default::default()
+                },
+            },
+        )]);
+
+        let bytes = encode(&Snapshot {
+            index: vec![&index],
+            base: None,
+            documents: &documents,
+            pending: &pending,
+            last_document_id: 302,
+            analyzer: AnalyzerKind::English,
+        });
+
+        (bytes.clone(), decode(&bytes).unwrap())
+    }
+
+    /// Changes the format version and updates the checksum to match.
+    fn set_version(bytes: &mut [u8], version: u32) {
+        bytes[4..8].copy_from_slice(&version.to_be_bytes());
+        let content_size = bytes.len() - CHECKSUM_SIZE;
+        let checksum = crc32fast::hash(&bytes[..content_size]);
+        bytes[content_size..].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buffer = Vec::new();
+            write_varint(&mut buffer, value);
+            assert_eq!(Reader::new(&buffer).varint().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let index = HashMap::from([
+            (
+                "rust".to_string(),
+                Postings::from([(3, vec![0, 2, 200]), (300, vec![0])]),
+            ),
+            ("legacy".to_string(), Postings::from([(3, vec![])])),
+            // Postings of a deleted document
+            ("deleted".to_string(), Postings::from([(7, vec![1])])),
+        ]);
+
+        let (_, state) = snapshot_round_trip(index.clone());
+
+        assert_eq!(state.last_document_id, 302);
+        assert_eq!(state.analyzer, AnalyzerKind::English);
+        assert_eq!(state.documents[&300].path, "uploads/b.txt");
+        assert_eq!(state.documents[&3].length, 4);
+        assert_eq!(state.documents[&3].metadata.tags["lang"], "en");
+        assert_eq!(state.documents[&3].metadata.size, 20);
+        assert_eq!(state.documents[&300].metadata, Metadata::default());
+        assert_eq!(state.pending[&301].path, "uploads/c.txt");
+        assert_eq!(
+            state.pending[&301].metadata.filename.as_deref(),
+            Some("c.txt")
+        );
+        assert_eq!(state.index.len(), 2);
+        assert_eq!(state.index["rust"], index["rust"]);
+        assert_eq!(state.index["legacy"], index["legacy"]);
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let (mut bytes, _) = snapshot_round_trip(HashMap::new());
+
+        bytes[40] ^= 1;
+        assert!(matches!(decode(&bytes), Err(LoadError::ChecksumMismatch)));
+
+        assert!(matches!(
+            decode(&bytes[..10]),
+            Err(LoadError::InvalidField("header"))
+        ));
+
+        let (mut bytes, _) = snapshot_round_trip(HashMap::new());
+        set_version(&mut bytes, 4);
+
+        assert!(matches!(
+            decode(&bytes),
+            Err(LoadError::UnsupportedVersion(4))
+        ));
+    }
+
+    #[test]
+    fn test_version_1_has_no_metadata() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(&2u64.to_be_bytes());
+        bytes.extend_from_slice(&[0; 16]);
+        write_string(&mut bytes, AnalyzerKind::English.name());
+
+        write_varint(&mut bytes, 1);
+        write_varint(&mut bytes, 0);
+        write_varint(&mut bytes, 3);
+        write_string(&mut bytes, "uploads/a.txt");
+
+        write_varint(&mut bytes, 1);
+        write_varint(&mut bytes, 1);
+        write_string(&mut bytes, "uploads/b.txt");
+
+        // No postings, so the dictionary starts right away
+        let offset = (bytes.len() as u64).to_be_bytes();
+        bytes[16..24].copy_from_slice(&offset);
+        bytes[24..32].copy_from_slice(&offset);
+        write_varint(&mut bytes, 0);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+
+        let state = decode(&bytes).unwrap();
+        assert_eq!(state.documents[&0].path, "uploads/a.txt");
+        assert_eq!(state.documents[&0].metadata, Metadata::default());
+        assert_eq!(state.pending[&1].path, "uploads/b.txt");
+        assert_eq!(state.pending[&1].metadata, Metadata::default());
+    }
+}
@@ -0,0 +1,77 @@
+use super::{Document, DocumentSet};
+use std::collections::HashMap;
+use std::ops::Deref;
+
+/// The indexed documents by ID, with their IDs as a bitmap and the sum of their
+/// lengths kept up to date, so that searches never have to collect them.
+#[derive(Debug, Default)]
+pub(super) struct Documents {
+    documents: HashMap<u64, Document>,
+    ids: DocumentSet,
+    // Sum of the lengths of all documents
+    total_length: u64,
+}
+
+impl Documents {
+    pub fn insert(&mut self, id: u64, document: Document) -> Option<Document> {
+        self.ids.insert(id);
+        self.total_length += document.length as u64;
+
+        let replaced = self.documents.insert(id, document);
+        if let Some(replaced) = &replaced {
+            self.total_length -= replaced.length as u64;
+        }
+
+        replaced
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Document> {
+        let removed = self.documents.remove(&id)?;
+        self.ids.remove(id);
+        self.total_length -= removed.length as u64;
+
+        Some(removed)
+    }
+
+    /// Moves a document to `generation`, e.g. once the state file holds it. Returns
+    /// `false` if there is no such document.
+    pub fn set_generation(&mut self, id: u64, generation: u64) -> bool {
+        match self.documents.get_mut(&id) {
+            Some(document) => {
+                document.generation = generation;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// IDs of all documents, the universe that `NOT` subtracts from.
+    pub fn ids(&self) -> &DocumentSet {
+        &self.ids
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+}
+
+impl From<HashMap<u64, Document>> for Documents {
+    fn from(documents: HashMap<u64, Document>) -> Self {
+        Documents {
+            ids: documents.keys().cloned().collect(),
+            total_length: documents
+                .values()
+                .map(|document| document.length as u64)
+                .sum(),
+            documents,
+        }
+    }
+}
+
+impl Deref for Documents {
+    type Target = HashMap<u64, Document>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.documents
+    }
+}
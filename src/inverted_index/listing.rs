@@ -0,0 +1,274 @@
+use super::Metadata;
+use std::cmp::Ordering;
+use thiserror::Error;
+
+/// What documents are listed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Id,
+    Filename,
+    Size,
+    UploadedAt,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub sort: SortKey,
+    pub descending: bool,
+    /// Maximum number of documents per page, all of them if `None`.
+    pub limit: Option<usize>,
+    /// Where the previous page ended, `None` for the first page.
+    pub cursor: Option<String>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ListError {
+    #[error(
+        "Invalid cursor {0:?}, cursors are only valid for the sort key they were returned for"
+    )]
+    InvalidCursor(String),
+}
+
+pub type ListResult<T> = std::result::Result<T, ListError>;
+
+/// A document as shown in a listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentInfo {
+    pub id: u64,
+    pub path: String,
+    /// `false` while the document is waiting to be indexed
+    pub indexed: bool,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub documents: Vec<DocumentInfo>,
+    /// Cursor for the next page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+/// Value of the sort key of a document. Ties are broken by ID, so that every
+/// document has a distinct position.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Number(u64),
+    Text(String),
+}
+
+impl SortKey {
+    fn value(&self, document: &DocumentInfo) -> SortValue {
+        match self {
+            SortKey::Id => SortValue::Number(document.id),
+            SortKey::Filename => {
+                SortValue::Text(document.metadata.filename.clone().unwrap_or_default())
+            }
+            SortKey::Size => SortValue::Number(document.metadata.size),
+            SortKey::UploadedAt => SortValue::Number(document.metadata.uploaded_at),
+        }
+    }
+}
+
+/// A position in a listing: the sort value and the ID of the last document of a page,
+/// written as `<id>:<value>`.
+struct Cursor {
+    id: u64,
+    value: SortValue,
+}
+
+impl Cursor {
+    fn after(sort: SortKey, document: &DocumentInfo) -> Self {
+        Cursor {
+            id: document.id,
+            value: sort.value(document),
+        }
+    }
+
+    fn parse(sort: SortKey, cursor: &str) -> ListResult<Self> {
+        let invalid = || ListError::InvalidCursor(cursor.to_string());
+
+        let (id, value) = cursor.split_once(':').ok_or_else(invalid)?;
+        let id = id.parse().map_err(|_| invalid())?;
+        let value = match sort {
+            SortKey::Filename => SortValue::Text(value.to_string()),
+            _ => SortValue::Number(value.parse().map_err(|_| invalid())?),
+        };
+
+        Ok(Cursor { id, value })
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            SortValue::Number(value) => write!(f, "{}:{value}", self.id),
+            SortValue::Text(value) => write!(f, "{}:{value}", self.id),
+        }
+    }
+}
+
+/// Sorts `documents` and returns the page that starts after the cursor in `options`.
+pub fn paginate(mut documents: Vec<DocumentInfo>, options: &ListOptions) -> ListResult<Page> {
+    let sort = options.sort;
+    let order = |a: &(SortValue, u64), b: &(SortValue, u64)| {
+        let ordering = a.cmp(b);
+        if options.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    };
+
+    let cursor = match &options.cursor {
+        Some(cursor) => Some(Cursor::parse(sort, cursor)?),
+        None => None,
+    };
+
+    documents.sort_by(|a, b| order(&(sort.value(a), a.id), &(sort.value(b), b.id)));
+
+    // Documents added or removed since the previous page do not shift the next one
+    let start = match cursor {
+        Some(cursor) => {
+            let cursor = (cursor.value, cursor.id);
+            documents.partition_point(|document| {
+                order(&(sort.value(document), document.id), &cursor) != Ordering::Greater
+            })
+        }
+        None => 0,
+    };
+
+    let mut documents = documents.split_off(start);
+    let next_cursor = match options.limit {
+        Some(limit) if documents.len() > limit => {
+            documents.truncate(limit);
+            documents
+                .last()
+                .map(|document| Cursor::after(sort, document).to_string())
+        }
+        _ => None,
+    };
+
+    Ok(Page {
+        documents,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(id: u64, filename: &str, size: u64) -> DocumentInfo {
+        DocumentInfo {
+            id,
+            path: format!("uploads/{id}.txt"),
+            indexed: true,
+            metadata: Metadata {
+                filename: Some(filename.to_string()),
+                size,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// IDs of every page, following the cursors until the last one.
+    fn pages(documents: &[DocumentInfo], mut options: ListOptions) -> Vec<Vec<u64>> {
+        let mut pages = Vec::new();
+
+        loop {
+            let page = paginate(documents.to_vec(), &options).unwrap();
+            pages.push(page.documents.iter().map(|document| document.id).collect());
+
+            match page.next_cursor {
+                Some(cursor) => options.cursor = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn test_pagination() {
+        let documents = vec![
+            document(3, "c.txt", 10),
+            document(0, "b:1.txt", 30),
+            document(1, "a.txt", 10),
+            document(2, "d.txt", 20),
+            document(4, "b:1.txt", 5),
+        ];
+
+        let options = |sort, descending| ListOptions {
+            sort,
+            descending,
+            limit: Some(2),
+            cursor: None,
+        };
+
+        assert_eq!(
+            pages(&documents, options(SortKey::Id, false)),
+            [vec![0, 1], vec![2, 3], vec![4]]
+        );
+        assert_eq!(
+            pages(&documents, options(SortKey::Id, true)),
+            [vec![4, 3], vec![2, 1], vec![0]]
+        );
+        assert_eq!(
+            pages(&documents, options(SortKey::Size, false)),
+            [vec![4, 1], vec![3, 2], vec![0]]
+        );
+        assert_eq!(
+            pages(&documents, options(SortKey::Filename, true)),
+            [vec![2, 3], vec![4, 0], vec![1]]
+        );
+
+        let all = ListOptions {
+            limit: None,
+            ..options(SortKey::Id, false)
+        };
+        assert_eq!(pages(&documents, all), [vec![0, 1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn test_cursor_survives_changes() {
+        let mut documents = vec![
+            document(0, "a", 1),
+            document(1, "b", 1),
+            document(2, "c", 1),
+        ];
+        let options = ListOptions {
+            limit: Some(2),
+            ..Default::default()
+        };
+
+        let page = paginate(documents.clone(), &options).unwrap();
+        documents.remove(1);
+        documents.push(document(3, "d", 1));
+
+        let options = ListOptions {
+            cursor: page.next_cursor,
+            ..options
+        };
+        let page = paginate(documents, &options).unwrap();
+        assert_eq!(
+            page.documents
+                .iter()
+                .map(|document| document.id)
+                .collect::<Vec<_>>(),
+            [2, 3]
+        );
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        let options = ListOptions {
+            sort: SortKey::Size,
+            cursor: Some("3:a.txt".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            paginate(Vec::new(), &options),
+            Err(ListError::InvalidCursor("3:a.txt".to_string()))
+        );
+    }
+}
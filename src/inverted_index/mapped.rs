@@ -0,0 +1,217 @@
+use super::binary::{self, DictionaryEntry, Reader, Sections};
+use super::storage::{LoadResult, State};
+use super::{DocumentSet, Postings};
+use log::{error, info};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// A binary state file mapped into memory. Only the position of every dictionary
+/// entry and of the terms of every document is kept on the heap, postings are
+/// decoded from the mapping when a term is looked up, so the operating system pages
+/// them in on demand.
+#[derive(Debug)]
+pub struct MappedIndex {
+    map: Mmap,
+    sections: Sections,
+    // Position of every dictionary entry, in term order
+    entries: Vec<usize>,
+    // ID -> Position of the document's terms in the forward section
+    forward: HashMap<u64, usize>,
+    // IDs of the saved documents that were deleted or replaced since
+    deleted: RwLock<DocumentSet>,
+}
+
+impl MappedIndex {
+    /// Returns the state of the mapped file, with the postings left in `state.base`.
+    ///
+    /// The checksum is verified up front, which reads the whole file once.
+    pub fn load(map: Mmap) -> LoadResult<State> {
+        let content = binary::verify(&map)?;
+        let (mut state, sections) = binary::decode_head(content)?;
+
+        // The terms of a document cannot be looked up in older files without decoding
+        // every posting list, so they are read onto the heap. The next save writes a
+        // file that can be mapped.
+        if sections.version < binary::FORWARD_VERSION {
+            info!("State file has no forward section, reading it onto the heap");
+            return binary::decode(&map);
+        }
+
+        let mut reader = Reader::new(content);
+        reader.position = sections.dictionary;
+
+        let count = reader.varint()?;
+        let mut entries = Vec::with_capacity((count as usize).min(reader.remaining()));
+        for _ in 0..count {
+            entries.push(reader.position);
+            binary::read_dictionary_entry(&mut reader)?;
+        }
+
+        let count = reader.varint()?;
+        let mut forward = HashMap::with_capacity((count as usize).min(reader.remaining()));
+        let mut id: u64 = 0;
+        for _ in 0..count {
+            id = id.wrapping_add(reader.varint()?);
+            forward.insert(id, reader.position);
+            binary::read_document_terms(&mut reader, entries.len())?;
+        }
+
+        state.base = Some(MappedIndex {
+            map,
+            sections,
+            entries,
+            forward,
+            deleted: RwLock::default(),
+        });
+
+        Ok(state)
+    }
+
+    fn content(&self) -> &[u8] {
+        binary::content(&self.map)
+    }
+
+    fn entry(&self, position: usize) -> DictionaryEntry<'_> {
+        let mut reader = Reader::new(self.content());
+        reader.position = position;
+
+        // Every entry was read successfully when the file was mapped
+        binary::read_dictionary_entry(&mut reader).unwrap()
+    }
+
+    /// All terms in sorted order.
+    pub fn terms(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .map(|&position| self.entry(position).term)
+    }
+
+    /// Terms from `start` on in sorted order, with the number of documents
+    /// containing them.
+    pub fn document_frequencies(&self, start: Bound<&str>) -> impl Iterator<Item = (&str, usize)> {
+        let first = self.entries.partition_point(|&position| {
+            let term = self.entry(position).term;
+            match start {
+                Bound::Included(start) => term < start,
+                Bound::Excluded(start) => term <= start,
+                Bound::Unbounded => false,
+            }
+        });
+
+        self.entries[first..].iter().map(|&position| {
+            let entry = self.entry(position);
+            (entry.term, entry.document_frequency)
+        })
+    }
+
+    /// Marks a saved document as deleted, so that its postings no longer count.
+    pub fn delete(&self, id: u64) {
+        self.deleted.write().unwrap().insert(id);
+    }
+
+    /// IDs of the saved documents that no longer count.
+    pub fn deleted(&self) -> RwLockReadGuard<'_, DocumentSet> {
+        self.deleted.read().unwrap()
+    }
+
+    /// Terms of a document saved in the mapped file, in sorted order.
+    pub fn document_terms(&self, id: u64) -> Option<Vec<&str>> {
+        let mut reader = Reader::new(self.content());
+        reader.position = *self.forward.get(&id)?;
+
+        // Every list was read successfully when the file was mapped
+        let indexes = binary::read_document_terms(&mut reader, self.entries.len()).unwrap();
+
+        Some(
+            indexes
+                .into_iter()
+                .map(|index| self.entry(self.entries[index]).term)
+                .collect(),
+        )
+    }
+
+    pub fn postings(&self, term: &str) -> Option<Postings> {
+        let index = self
+            .entries
+            .binary_search_by(|&position| self.entry(position).term.cmp(term))
+            .ok()?;
+        let entry = self.entry(self.entries[index]);
+
+        match binary::decode_postings(self.content(), &self.sections, entry.offset) {
+            Ok(postings) => Some(postings),
+            Err(e) => {
+                error!("Failed to decode the postings of {term:?}: {e}");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::storage::{self, Snapshot};
+    use super::super::test_dir::TestDir;
+    use super::super::{AnalyzerKind, Document};
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_postings_are_read_from_the_mapping() {
+        let dir = TestDir::new();
+        let path = dir.join("index.bin");
+        let index = HashMap::from([
+            (
+                "rust".to_string(),
+                Postings::from([(0, vec![1]), (1, vec![0, 4])]),
+            ),
+            ("async".to_string(), Postings::from([(1, vec![2])])),
+            ("zig".to_string(), Postings::from([(0, vec![0])])),
+        ]);
+        let documents = (0..2)
+            .map(|id| {
+                let document = Document {
+                    path: format!("uploads/{id}.txt"),
+                    length: 5,
+                    generation: 0,
+                    metadata: Default::default(),
+                };
+                (id, document)
+            })
+            .collect();
+
+        let bytes = binary::encode(&Snapshot {
+            index: vec![&index],
+            base: None,
+            documents: &documents,
+            pending: &HashMap::new(),
+            last_document_id: 2,
+            analyzer: AnalyzerKind::Standard,
+        });
+        std::fs::write(&path, bytes).unwrap();
+
+        let state = storage::read_state(&path, true).unwrap().unwrap();
+        assert!(state.index.is_empty());
+        assert_eq!(state.documents.len(), 2);
+
+        let base = state.base.unwrap();
+        assert_eq!(base.terms().collect::<Vec<_>>(), ["async", "rust", "zig"]);
+        assert_eq!(
+            base.document_frequencies(Bound::Unbounded)
+                .collect::<Vec<_>>(),
+            [("async", 1), ("rust", 2), ("zig", 1)]
+        );
+        assert_eq!(
+            base.document_frequencies(Bound::Excluded("rust"))
+                .collect::<Vec<_>>(),
+            [("zig", 1)]
+        );
+        assert_eq!(base.postings("rust").as_ref(), index.get("rust"));
+        assert_eq!(base.postings("zig").as_ref(), index.get("zig"));
+        assert!(base.postings("python").is_none());
+        assert_eq!(base.document_terms(0), Some(vec!["rust", "zig"]));
+        assert_eq!(base.document_terms(1), Some(vec!["async", "rust"]));
+        assert_eq!(base.document_terms(2), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What is known about an uploaded file besides its content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Name of the file on the client
+    pub filename: Option<String>,
+    /// MIME type of the content, as given by the client
+    pub content_type: Option<String>,
+    /// Custom key/value pairs given by the client
+    pub tags: BTreeMap<String, String>,
+    /// Seconds since the Unix epoch, 0 for documents uploaded before metadata existed
+    pub uploaded_at: u64,
+    /// Size of the file in bytes
+    pub size: u64,
+}
+
+impl Metadata {
+    /// Metadata sent by a client for a file of `size` bytes that was received just now.
+    pub fn received(mut self, size: u64) -> Self {
+        self.uploaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        self.size = size;
+        self
+    }
+
+    /// Metadata of a file found in the uploads directory without a document, whose
+    /// original metadata was lost. Only the size and modification time are known.
+    pub fn recovered(file: &std::fs::Metadata) -> Self {
+        Metadata {
+            uploaded_at: file
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            size: file.len(),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "filename": self.filename,
+            "content_type": self.content_type,
+            "tags": self.tags,
+            "uploaded_at": self.uploaded_at,
+            "size": self.size,
+        })
+    }
+
+    /// Returns `None` if `value` is not an object or a field has the wrong type.
+    /// Missing fields keep their defaults, so clients only send what they know.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let object = value.as_object()?;
+
+        let string = |field: &str| match object.get(field) {
+            None | Some(serde_json::Value::Null) => Some(None),
+            Some(value) => value.as_str().map(|value| Some(value.to_string())),
+        };
+        let number = |field: &str| match object.get(field) {
+            None => Some(0),
+            Some(value) => value.as_u64(),
+        };
+
+        let tags = match object.get("tags") {
+            None => BTreeMap::new(),
+            Some(tags) => tags
+                .as_object()?
+                .iter()
+                .map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect::<Option<_>>()?,
+        };
+
+        Some(Metadata {
+            filename: string("filename")?,
+            content_type: string("content_type")?,
+            tags,
+            uploaded_at: number("uploaded_at")?,
+            size: number("size")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let metadata = Metadata {
+            filename: Some("notes.txt".to_string()),
+            content_type: Some("text/plain".to_string()),
+            tags: BTreeMap::from([("lang".to_string(), "en".to_string())]),
+            uploaded_at: 1_700_000_000,
+            size: 42,
+        };
+
+        assert_eq!(Metadata::from_json(&metadata.to_json()), Some(metadata));
+        assert_eq!(
+            Metadata::from_json(&serde_json::json!({})),
+            Some(Metadata::default())
+        );
+        assert_eq!(
+            Metadata::from_json(&serde_json::json!({ "tags": { "lang": 1 } })),
+            None
+        );
+        assert_eq!(Metadata::from_json(&serde_json::json!("notes.txt")), None);
+    }
+}
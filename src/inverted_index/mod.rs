@@ -1,174 +1,1038 @@
+mod analysis;
+mod binary;
+mod documents;
+mod listing;
+mod mapped;
+mod metadata;
+mod postings;
+mod query;
+mod ranking;
+mod segment;
+mod shard;
+mod snippet;
+mod storage;
+#[cfg(test)]
+mod test_dir;
 #[cfg(test)]
 mod tests;
 mod tokenize;
+mod vocabulary;
+mod wal;
+
+pub use analysis::{Analyzer, AnalyzerKind};
+pub use listing::{DocumentInfo, ListError, ListOptions, ListResult, Page, SortKey};
+pub use metadata::Metadata;
+pub use postings::Postings;
+pub use query::{Operator, QueryError, QueryResult, SearchOptions, MAX_EXPANSIONS};
+pub use ranking::{SearchHit, SearchResults};
+pub use storage::{LoadError, LoadResult, Recovery};
 
 use super::STATE_FILE;
-use log::{error, info};
-use std::collections::HashSet;
-use std::collections::{BTreeSet, HashMap};
+use documents::Documents;
+use log::{debug, error, info, warn};
+use mapped::MappedIndex;
+use postings::LivePostings;
+use query::{Pattern, Query, MAX_EDIT_DISTANCE};
+use ranking::Bm25;
+use segment::Segment;
+use shard::{Shard, SHARD_COUNT};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 use std::sync::atomic::AtomicU64;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use storage::{Snapshot, State};
+use vocabulary::Vocabulary;
+use wal::{Operation, WriteAheadLog};
+
+/// Maximum number of spelling suggestions for a query term without postings.
+const SUGGESTIONS: usize = 5;
+
+/// Maximum number of hits of a page that get a snippet, as each one re-reads a file.
+const MAX_SNIPPETS: usize = 20;
+
+/// IDs of documents, as a compressed bitmap with fast intersection, union and
+/// difference
+pub type DocumentSet = roaring::RoaringTreemap;
+
+#[derive(Debug, Clone)]
+struct Document {
+    path: String,
+    // Number of words, used to normalize relevance scores
+    length: u32,
+    // Generation the document was indexed in, see `Segment`
+    generation: u64,
+    metadata: Metadata,
+}
+
+/// Terms of the version of a document indexed in `generation`.
+#[derive(Debug, Clone)]
+struct DocumentTerms {
+    generation: u64,
+    terms: Vec<String>,
+}
+
+/// Changes made by [`InvertedIndex::reconcile_uploads`].
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciled {
+    /// IDs registered for files without a document, in the order they were uploaded
+    pub added: Vec<u64>,
+    /// IDs of documents whose files were gone
+    pub removed: Vec<u64>,
+}
+
+/// An uploaded file that is being received and not registered yet, which
+/// [`InvertedIndex::reconcile_uploads`] leaves alone until the guard is dropped.
+#[derive(Debug)]
+pub struct Upload<'a> {
+    index: &'a InvertedIndex,
+    path: String,
+}
+
+impl Upload<'_> {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        self.index.uploading.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// A document that is registered but not indexed yet.
+#[derive(Debug, Clone, PartialEq)]
+struct PendingDocument {
+    path: String,
+    metadata: Metadata,
+}
 
 #[derive(Debug)]
 pub struct InvertedIndex {
-    // Word -> IDs
-    index: Arc<RwLock<HashMap<String, BTreeSet<u64>>>>,
-    // ID -> Document file path
-    documents: Arc<RwLock<HashMap<u64, String>>>,
+    // Segments of the terms partitioned by hash, so that concurrent indexing does not
+    // contend on a single lock
+    shards: Vec<Shard>,
+    // Generation counter, 0 is the generation of documents loaded from the state file
+    next_generation: AtomicU64,
+    // The mapped state file, when the index is opened with mmap. Segments only hold
+    // the documents indexed since then.
+    base: RwLock<Option<Arc<MappedIndex>>>,
+    mmap: bool,
+    // Terms of all indexed documents with their document frequencies, for expanding
+    // patterns and completing prefixes. Locked after the documents.
+    vocabulary: Vocabulary,
+    // ID -> Document
+    documents: Arc<RwLock<Documents>>,
+    // ID -> Terms of the indexed version of a document, so that deleting or replacing
+    // it only touches those terms. Documents of generation 0 in the mapped state file
+    // are looked up there instead. Locked after the documents.
+    forward_index: RwLock<HashMap<u64, DocumentTerms>>,
+    // ID -> Documents that are registered but not indexed yet, or the new version of
+    // an updated document
+    pending: Arc<RwLock<HashMap<u64, PendingDocument>>>,
     // ID counter
     last_document_id: AtomicU64,
+    // Where the index is saved to and loaded from
+    state_file: String,
+    // Turns both document contents and queries into terms
+    analyzer: Analyzer,
+    // Operations since the last save, also serializes them against saving
+    log: Mutex<WriteAheadLog>,
+    // Paths of uploads that are being received and not registered yet. Held while the
+    // uploads directory is reconciled, and locked before the pending documents.
+    uploading: Mutex<HashSet<String>>,
+    // Files no document refers to any more that are about to be removed. Locked
+    // after the documents.
+    removing: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub state_file: String,
+    /// Analyzer used when a new index is created. Existing indexes keep the analyzer
+    /// they were built with.
+    pub analyzer: AnalyzerKind,
+    pub recovery: Recovery,
+    /// Map the state file into memory instead of reading all postings onto the heap.
+    pub mmap: bool,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            state_file: STATE_FILE.to_string(),
+            analyzer: AnalyzerKind::default(),
+            recovery: Recovery::default(),
+            mmap: false,
+        }
+    }
 }
 
 impl InvertedIndex {
+    /// Loads the index from the default state file, panicking if it cannot be loaded.
     pub fn new() -> Self {
-        if let Some(index) = Self::load() {
+        Self::with_config(IndexConfig::default()).expect("Failed to load index")
+    }
+
+    pub fn with_state_file(state_file: impl Into<String>) -> Self {
+        Self::with_config(IndexConfig {
+            state_file: state_file.into(),
+            ..Default::default()
+        })
+        .expect("Failed to load index")
+    }
+
+    pub fn with_config(config: IndexConfig) -> LoadResult<Self> {
+        let IndexConfig {
+            state_file,
+            analyzer,
+            recovery,
+            mmap,
+        } = config;
+
+        let migrated = Self::migrate_legacy_state_file(&state_file)?;
+
+        let state = match storage::read_state(&state_file, mmap) {
+            Ok(state) => state,
+            Err(e) if recovery == Recovery::Fail => return Err(e),
+            Err(e) => {
+                error!("Failed to load index: {e}");
+
+                let state = if recovery == Recovery::Backup {
+                    info!("Restoring index from backup");
+
+                    let backup = storage::read_state(&storage::backup_file(&state_file), mmap)?;
+                    Some(backup.ok_or(LoadError::NoBackup)?)
+                } else {
+                    None
+                };
+
+                let corrupt_file = storage::corrupt_file(&state_file);
+                warn!("Moving the state file that failed to load to {corrupt_file}");
+
+                std::fs::rename(&state_file, &corrupt_file).map_err(LoadError::FailedToRead)?;
+
+                state
+            }
+        };
+
+        let state = if let Some(state) = state {
             info!("State file found, loading index");
-            return index;
-        }
 
-        info!("State file not found, creating new index");
+            if state.analyzer != analyzer {
+                warn!(
+                    "Index was built with the {} analyzer, ignoring {}",
+                    state.analyzer.name(),
+                    analyzer.name()
+                );
+            }
 
-        InvertedIndex {
-            index: Arc::new(RwLock::new(HashMap::new())),
-            documents: Arc::new(RwLock::new(HashMap::new())),
-            last_document_id: AtomicU64::new(0),
-        }
-    }
+            state
+        } else {
+            info!(
+                "State file not found, creating new index with the {} analyzer",
+                analyzer.name()
+            );
 
-    fn load() -> Option<Self> {
-        let raw_data = std::fs::read_to_string(STATE_FILE).ok()?;
+            State {
+                analyzer,
+                ..Default::default()
+            }
+        };
 
-        let raw_data: serde_json::Value =
-            serde_json::from_str(&raw_data).expect("Failed to parse JSON");
+        let log = WriteAheadLog::open(&Self::log_file(&state_file))
+            .map_err(LoadError::FailedToReadLog)?;
 
-        let index = raw_data["index"]
-            .as_object()
-            .expect("Failed to parse index")
+        // Terms of a mapped state file stay in its dictionary
+        let terms: BTreeMap<String, usize> = state
+            .index
             .iter()
-            .map(|(word, ids)| {
-                let ids = ids
-                    .as_array()
-                    .expect("Failed to parse IDs")
+            .map(|(term, postings)| {
+                let frequency = postings
+                    .documents()
                     .iter()
-                    .map(|id| id.as_u64().expect("Failed to parse ID") as u64)
+                    .filter(|id| state.documents.contains_key(id))
+                    .count();
+                (term.clone(), frequency)
+            })
+            .filter(|&(_, frequency)| frequency > 0)
+            .collect();
+        let base = state.base.map(Arc::new);
+
+        let mut forward_index: HashMap<u64, DocumentTerms> = HashMap::new();
+        for (term, postings) in &state.index {
+            for id in postings
+                .documents()
+                .iter()
+                .filter(|id| state.documents.contains_key(id))
+            {
+                forward_index
+                    .entry(id)
+                    .or_insert_with(|| DocumentTerms {
+                        generation: 0,
+                        terms: Vec::new(),
+                    })
+                    .terms
+                    .push(term.clone());
+            }
+        }
+
+        let mut postings_by_shard: Vec<HashMap<String, Postings>> =
+            (0..SHARD_COUNT).map(|_| HashMap::new()).collect();
+        for (term, postings) in state.index {
+            postings_by_shard[shard::shard_of(&term)].insert(term, postings);
+        }
+
+        let shards = postings_by_shard
+            .into_iter()
+            .map(|postings| {
+                let documents = postings
+                    .values()
+                    .flat_map(|postings| postings.documents().iter())
+                    .filter(|id| state.documents.contains_key(id))
+                    .map(|id| (id, 0))
                     .collect();
-                (word.to_string(), ids)
+
+                Shard::new(Segment::new(postings, documents))
             })
             .collect();
 
-        let documents = raw_data["documents"]
-            .as_object()
-            .expect("Failed to parse documents")
+        let index = InvertedIndex {
+            shards,
+            next_generation: AtomicU64::new(1),
+            vocabulary: Vocabulary::new(base.clone(), terms),
+            base: RwLock::new(base),
+            mmap,
+            documents: Arc::new(RwLock::new(Documents::from(state.documents))),
+            forward_index: RwLock::new(forward_index),
+            pending: Arc::new(RwLock::new(state.pending)),
+            last_document_id: AtomicU64::new(state.last_document_id),
+            state_file,
+            analyzer: Analyzer::new(state.analyzer),
+            log: Mutex::new(log),
+            uploading: Mutex::new(HashSet::new()),
+            removing: Mutex::new(HashSet::new()),
+        };
+
+        index.recover()?;
+
+        if migrated {
+            match index.save() {
+                Ok(()) => info!("Index converted to the binary format"),
+                Err(e) => error!("Failed to convert index to the binary format: {e}"),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Copies a state file saved as JSON by an older version (`index.json` next to
+    /// `index.bin`) to `state_file`, together with its write-ahead log, if there is
+    /// no `state_file` yet. The original is kept in case of a downgrade.
+    ///
+    /// Returns whether there was anything to migrate.
+    fn migrate_legacy_state_file(state_file: &str) -> LoadResult<bool> {
+        let legacy_state_file = Path::new(state_file).with_extension("json");
+
+        if Path::new(state_file).exists()
+            || !legacy_state_file.exists()
+            || legacy_state_file == Path::new(state_file)
+        {
+            return Ok(false);
+        }
+
+        info!("Migrating {} to {state_file}", legacy_state_file.display());
+
+        std::fs::copy(&legacy_state_file, state_file).map_err(LoadError::FailedToRead)?;
+
+        let legacy_log_file = Self::log_file(&legacy_state_file.to_string_lossy());
+        if Path::new(&legacy_log_file).exists() {
+            std::fs::rename(&legacy_log_file, Self::log_file(state_file))
+                .map_err(LoadError::FailedToReadLog)?;
+        }
+
+        Ok(true)
+    }
+
+    fn log_file(state_file: &str) -> String {
+        format!("{state_file}.wal")
+    }
+
+    /// Replays the operations logged since the last save and indexes the documents
+    /// that were uploaded but not indexed before the server stopped.
+    fn recover(&self) -> LoadResult<()> {
+        let operations = WriteAheadLog::read(&Self::log_file(&self.state_file))
+            .map_err(LoadError::FailedToReadLog)?;
+
+        if !operations.is_empty() {
+            info!(
+                "Replaying {} operations from the write-ahead log",
+                operations.len()
+            );
+        }
+
+        for operation in operations {
+            match operation {
+                Operation::Add { id, path, metadata } => {
+                    self.last_document_id
+                        .fetch_max(id + 1, std::sync::atomic::Ordering::SeqCst);
+
+                    // The operation may have been logged right before the last save
+                    if !self.documents.read().unwrap().contains_key(&id) {
+                        let document = PendingDocument { path, metadata };
+                        self.pending.write().unwrap().insert(id, document);
+                    }
+                }
+                Operation::Update { id, path, metadata } => {
+                    // Indexing the update may have finished right before the last save
+                    if self
+                        .get_document_path(id)
+                        .is_some_and(|current| current != path)
+                    {
+                        let document = PendingDocument { path, metadata };
+                        self.pending.write().unwrap().insert(id, document);
+                    }
+                }
+                Operation::Delete { id } => {
+                    let paths = self.remove_document(id);
+
+                    if let Err(e) = self.purge_document(id, &paths) {
+                        warn!("Failed to remove file of deleted document {id}: {e}");
+                    }
+                }
+            }
+        }
+
+        let pending: Vec<u64> = self.pending.read().unwrap().keys().cloned().collect();
+
+        for document_id in pending {
+            self.index_document(document_id);
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` on a consistent view of the whole index, merging the segments of
+    /// every shard into one first. Returns the merged segments along with the result.
+    fn with_snapshot<R>(&self, f: impl FnOnce(&Snapshot) -> R) -> (R, Vec<Arc<Segment>>) {
+        let _merging: Vec<_> = self.shards.iter().map(Shard::lock_merges).collect();
+
+        let pending = self.pending.read().unwrap();
+
+        // No segments are added while the documents are locked
+        let documents = self.documents.read().unwrap();
+
+        let compacted: Vec<Arc<Segment>> = self
+            .shards
             .iter()
-            .map(|(id, document)| {
-                let id = id.parse().expect("Failed to parse ID");
-                let document = document
-                    .as_str()
-                    .expect("Failed to parse document")
-                    .to_string();
-                (id, document)
+            .map(|shard| {
+                let segments = shard.segments();
+
+                match segments.as_slice() {
+                    [segment]
+                        if segment
+                            .documents()
+                            .all(|(id, generation)| is_live(&documents, id, generation)) =>
+                    {
+                        Arc::clone(segment)
+                    }
+                    _ => {
+                        let merged = Arc::new(Segment::merge(&segments, |id, generation| {
+                            is_live(&documents, id, generation)
+                        }));
+                        shard.replace(&segments, Arc::clone(&merged));
+                        merged
+                    }
+                }
             })
             .collect();
 
-        let last_document_id = raw_data["last_document_id"]
-            .as_u64()
-            .expect("Failed to parse last_document_id") as u64;
+        let base = self.base.read().unwrap().clone();
 
-        Some(Self {
-            index: Arc::new(RwLock::new(index)),
-            documents: Arc::new(RwLock::new(documents)),
-            last_document_id: AtomicU64::new(last_document_id),
-        })
+        let result = f(&Snapshot {
+            index: compacted
+                .iter()
+                .map(|segment| segment.all_postings())
+                .collect(),
+            base: base.as_deref(),
+            documents: &documents,
+            pending: &pending,
+            last_document_id: self
+                .last_document_id
+                .load(std::sync::atomic::Ordering::SeqCst),
+            analyzer: self.analyzer.kind(),
+        });
+
+        (result, compacted)
     }
 
-    pub fn save(&self) {
+    /// Writes the whole index to the state file and empties the write-ahead log.
+    pub fn save(&self) -> std::io::Result<()> {
         info!("Saving index state");
-        let index = self.index.read().unwrap();
+        let mut log = self.log.lock().unwrap();
 
-        let documents = self.documents.read().unwrap();
+        // The vocabulary does not change while the documents are locked for the snapshot
+        let ((data, vocabulary), compacted) = self.with_snapshot(|snapshot| {
+            let vocabulary = self.mmap.then(|| self.vocabulary.changes());
+            (binary::encode(snapshot), vocabulary)
+        });
+
+        storage::write_atomically(&self.state_file, &data)?;
+
+        if let Some(vocabulary) = vocabulary {
+            // The old mapping stays valid, so the index keeps working without the new one
+            if let Err(e) = self.remap(compacted, vocabulary) {
+                error!("Failed to map the saved index: {e}");
+            }
+        }
+
+        log.truncate()
+    }
+
+    /// Maps the state file that was just saved in place of the old one, and drops the
+    /// `saved` segments whose documents it now holds. `vocabulary` are the changes to
+    /// the vocabulary the saved file holds.
+    fn remap(&self, saved: Vec<Arc<Segment>>, vocabulary: BTreeMap<String, i64>) -> LoadResult<()> {
+        let (saved_documents, base) = storage::read_state(&self.state_file, true)?
+            .and_then(|state| Some((state.documents, state.base?)))
+            .ok_or(LoadError::InvalidField("header"))?;
+
+        let mut documents = self.documents.write().unwrap();
+        let mut forward_index = self.forward_index.write().unwrap();
+
+        for (shard, segment) in self.shards.iter().zip(saved) {
+            // Documents saved in the state file count in the mapping from now on, and
+            // their terms are looked up there as well
+            for (id, generation) in segment.documents() {
+                if is_live(&documents, id, generation) {
+                    documents.set_generation(id, 0);
+                    forward_index.remove(&id);
+                }
+            }
+
+            shard.remove(&[segment]);
+        }
+
+        // Documents deleted or replaced since the snapshot was taken
+        for &id in saved_documents.keys() {
+            if !is_live(&documents, id, 0) {
+                base.delete(id);
+            }
+        }
+
+        let base = Arc::new(base);
+        self.vocabulary.rebase(Arc::clone(&base), vocabulary);
+        *self.base.write().unwrap() = Some(base);
 
-        let last_document_id = self
+        Ok(())
+    }
+
+    /// Writes the index to `path` in the JSON format used before the binary one.
+    pub fn export_json(&self, path: &str) -> std::io::Result<()> {
+        let (data, _) = self.with_snapshot(storage::to_json);
+
+        std::fs::write(path, serde_json::to_vec_pretty(&data)?)
+    }
+
+    /// Reserves a new document ID for the file at `path` without indexing it yet.
+    ///
+    /// The document can be downloaded or deleted right away, but becomes searchable
+    /// (and is counted by [`InvertedIndex::get_document_count`]) only once
+    /// [`InvertedIndex::index_document`] has run for it.
+    ///
+    /// The document is written to the write-ahead log before this returns.
+    pub fn register_document(&self, path: String, metadata: Metadata) -> std::io::Result<u64> {
+        let mut log = self.log.lock().unwrap();
+
+        let document_id = self
             .last_document_id
-            .load(std::sync::atomic::Ordering::SeqCst);
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
-        let data = serde_json::json!({
-            "index": index.clone(),
-            "documents": documents.clone(),
-            "last_document_id": last_document_id,
-        });
+        log.append(&Operation::Add {
+            id: document_id,
+            path: path.clone(),
+            metadata: metadata.clone(),
+        })?;
 
-        let data = serde_json::to_string_pretty(&data).expect("Failed to serialize JSON");
+        let mut pending = self.pending.write().unwrap();
+        pending.insert(document_id, PendingDocument { path, metadata });
+
+        Ok(document_id)
+    }
+
+    /// Logs new content for an existing document, which keeps its ID. Searches and
+    /// downloads see the old content and metadata until
+    /// [`InvertedIndex::index_document`] swaps the new ones in.
+    ///
+    /// Returns `false` if the document does not exist.
+    pub fn register_update(
+        &self,
+        document_id: u64,
+        path: String,
+        metadata: Metadata,
+    ) -> std::io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+
+        if !self.document_exists(document_id) {
+            return Ok(false);
+        }
+
+        log.append(&Operation::Update {
+            id: document_id,
+            path: path.clone(),
+            metadata: metadata.clone(),
+        })?;
+
+        let mut pending = self.pending.write().unwrap();
+        let superseded = pending.insert(document_id, PendingDocument { path, metadata });
+        if let Some(superseded) = &superseded {
+            self.removing
+                .lock()
+                .unwrap()
+                .insert(superseded.path.clone());
+        }
+        drop(pending);
+        drop(log);
+
+        // An earlier version that has not been indexed yet will never be
+        if let Some(superseded) = superseded {
+            if let Err(e) = self.remove_file(&superseded.path) {
+                warn!("Failed to remove superseded file {}: {e}", superseded.path);
+            }
+        }
 
-        std::fs::write(STATE_FILE, data).expect("Failed to write file");
+        Ok(true)
     }
 
-    pub fn add_document(&self, path: String) {
-        let content = if let Ok(content) = std::fs::read_to_string(&path) {
-            tokenize::tokenize(&content)
+    /// Analyzes a registered document and moves it into the index, replacing the
+    /// previous version of an updated document.
+    pub fn index_document(&self, document_id: u64) {
+        let Some(PendingDocument { path, metadata }) =
+            self.pending.read().unwrap().get(&document_id).cloned()
+        else {
+            error!("Document {document_id} is not waiting to be indexed");
+            return;
+        };
+
+        let tokens = if let Ok(content) = std::fs::read_to_string(&path) {
+            self.analyzer.analyze(&content)
         } else {
             error!("Failed to read document: {path}");
             return;
         };
 
-        let document_id = self
-            .last_document_id
+        let length = tokens.len() as u32;
+
+        let mut words: HashMap<String, Vec<u32>> = HashMap::new();
+        for token in tokens {
+            words.entry(token.text).or_default().push(token.position);
+        }
+
+        let generation = self
+            .next_generation
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
+        let mut words_by_shard: Vec<HashMap<String, Vec<u32>>> =
+            (0..SHARD_COUNT).map(|_| HashMap::new()).collect();
+        for (word, positions) in words {
+            words_by_shard[shard::shard_of(&word)].insert(word, positions);
+        }
+
+        let segments: Vec<_> = words_by_shard
+            .into_iter()
+            .map(|words| {
+                (!words.is_empty())
+                    .then(|| Arc::new(Segment::with_document(document_id, generation, words)))
+            })
+            .collect();
+
+        let mut pending = self.pending.write().unwrap();
+
+        // The document may have been deleted or updated again while its content was
+        // being read
+        if pending
+            .get(&document_id)
+            .is_none_or(|pending| pending.path != path)
         {
-            let mut documents = self.documents.write().unwrap();
-            documents.insert(document_id, path.clone());
+            info!("Document {document_id} was deleted or updated before it could be indexed");
+            return;
+        }
+        pending.remove(&document_id);
+
+        let mut documents = self.documents.write().unwrap();
+        let replaced = documents.insert(
+            document_id,
+            Document {
+                path: path.clone(),
+                length,
+                generation,
+                metadata,
+            },
+        );
+
+        // Terms are counted together with the document, every shard holds distinct ones
+        let terms: Vec<String> = segments
+            .iter()
+            .flatten()
+            .flat_map(|segment| segment.all_postings().keys().cloned())
+            .collect();
+        self.vocabulary.add(terms.iter().map(String::as_str));
+
+        if let Some(replaced) = &replaced {
+            self.vocabulary.remove(
+                self.document_terms(document_id, replaced)
+                    .iter()
+                    .map(String::as_str),
+            );
+            self.delete_version(document_id, replaced.generation);
         }
+        self.forward_index
+            .write()
+            .unwrap()
+            .insert(document_id, DocumentTerms { generation, terms });
 
+        // Segments are added while the documents are locked, so that a merge never sees
+        // a segment before its document. Each shard is locked only for a single push.
+        for (shard, segment) in self.shards.iter().zip(segments) {
+            if let Some(segment) = segment {
+                shard.push(segment);
+            }
+        }
+
+        let replaced = replaced.map(|replaced| replaced.path);
+        if let Some(replaced) = replaced.as_ref().filter(|&replaced| *replaced != path) {
+            self.removing.lock().unwrap().insert(replaced.clone());
+        }
+
+        drop(documents);
+        drop(pending);
+
+        // The postings of the previous version are dropped the next time their segment
+        // is merged
+        if let Some(replaced) = replaced.filter(|replaced| *replaced != path) {
+            if let Err(e) = self.remove_file(&replaced) {
+                warn!("Failed to remove file of replaced document: {e}");
+            }
+        }
+    }
+
+    /// Terms of the indexed version of a document, which must be the one in the
+    /// locked document table.
+    fn document_terms(&self, id: u64, document: &Document) -> Vec<String> {
+        if let Some(terms) = self
+            .forward_index
+            .read()
+            .unwrap()
+            .get(&id)
+            .filter(|terms| terms.generation == document.generation)
         {
-            let mut index = self.index.write().unwrap();
+            return terms.terms.clone();
+        }
 
-            for word in content {
-                index
-                    .entry(word)
-                    .and_modify(|ids| {
-                        ids.insert(document_id);
-                    })
-                    .or_insert_with(|| {
-                        let mut ids = BTreeSet::new();
-                        ids.insert(document_id);
-                        ids
-                    });
+        let base = self.base.read().unwrap().clone();
+        match base.as_deref().and_then(|base| base.document_terms(id)) {
+            Some(terms) if document.generation == 0 => {
+                terms.into_iter().map(str::to_string).collect()
+            }
+            _ => {
+                warn!("Terms of document {id} are unknown");
+                Vec::new()
             }
         }
     }
 
-    pub fn search(&self, query: &str) -> HashSet<u64> {
-        let words = tokenize::tokenize(query);
+    /// Marks the version of a document indexed in `generation` as deleted wherever its
+    /// postings are stored, once the locked document table no longer holds it.
+    fn delete_version(&self, id: u64, generation: u64) {
+        if generation == 0 {
+            if let Some(base) = self.base.read().unwrap().as_deref() {
+                base.delete(id);
+            }
+        }
+
+        for shard in &self.shards {
+            shard.delete(id, generation);
+        }
+    }
+
+    /// Removes a file that was added to `removing` when its document let go of it.
+    /// Files that are already gone count as removed.
+    fn remove_file(&self, path: &str) -> std::io::Result<()> {
+        let removed = match std::fs::remove_file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            removed => removed,
+        };
+
+        self.removing.lock().unwrap().remove(path);
+
+        removed
+    }
+
+    /// Runs the merges chosen by the merge policy in every shard where no other merge
+    /// is running.
+    pub fn merge_segments(&self) {
+        for shard in &self.shards {
+            if let Some(_merging) = shard.try_lock_merges() {
+                self.merge_shard(shard);
+            }
+        }
+    }
+
+    fn merge_shard(&self, shard: &Shard) {
+        loop {
+            let segments = shard.segments();
+
+            let Some(selected) = segment::select_merge(&segments) else {
+                break;
+            };
+
+            // Liveness is checked up front, so that the documents are not locked while
+            // merging. Documents deleted in the meantime are dropped by a later merge.
+            let live: HashSet<(u64, u64)> = {
+                let documents = self.documents.read().unwrap();
+
+                selected
+                    .iter()
+                    .flat_map(|segment| segment.documents())
+                    .filter(|&(id, generation)| is_live(&documents, id, generation))
+                    .collect()
+            };
+
+            let merged =
+                Segment::merge(&selected, |id, generation| live.contains(&(id, generation)));
+
+            debug!(
+                "Merged {} segments into one with {} documents",
+                selected.len(),
+                merged.len()
+            );
+
+            shard.replace(&selected, Arc::new(merged));
+        }
+    }
 
-        let index = self.index.read().unwrap();
+    pub fn add_document(&self, path: String) -> std::io::Result<u64> {
+        let document_id = self.register_document(path, Metadata::default())?;
 
-        let result = words
+        self.index_document(document_id);
+
+        Ok(document_id)
+    }
+
+    /// Returns the documents matching `query`, ranked by their BM25 relevance score.
+    pub fn search(&self, query: &str, options: &SearchOptions) -> QueryResult<Vec<SearchHit>> {
+        self.search_page(query, options).map(|results| results.hits)
+    }
+
+    /// Like [`InvertedIndex::search`], but also counts the hits outside of the page
+    /// selected by `options.offset` and `options.limit`.
+    pub fn search_page(&self, query: &str, options: &SearchOptions) -> QueryResult<SearchResults> {
+        let query = Query::parse(query, options.default_operator, &self.analyzer)?;
+
+        // Terms as typed, before patterns are expanded into terms of the index
+        let typed: Vec<String> = query
+            .scoring_terms()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+
+        let query = query.expand(&|pattern, limit| self.vocabulary.matching(pattern, limit))?;
+
+        let documents = self.documents.read().unwrap();
+
+        let bm25 = Bm25::new(documents.len(), documents.total_length());
+
+        let base = self.base.read().unwrap().clone();
+        let terms = query.terms();
+
+        // The gathered postings borrow from the postings decoded from the mapped state
+        // file and from the segments, which stay the same while the documents are locked
+        let base_postings: HashMap<&str, Postings> = terms
             .iter()
-            .flat_map(|word| index.get(word))
-            .flatten()
-            .cloned()
+            .filter_map(|&term| Some((term, base.as_deref()?.postings(term)?)))
+            .collect();
+        let segments: HashMap<&str, Vec<Arc<Segment>>> = terms
+            .iter()
+            .map(|&term| (term, self.shards[shard::shard_of(term)].segments()))
+            .collect();
+
+        let gathered: HashMap<&str, LivePostings> = terms
+            .iter()
+            .map(|&term| {
+                let base = base
+                    .as_deref()
+                    .zip(base_postings.get(term))
+                    .map(|(base, postings)| (postings, base));
+                (term, live_postings(term, base, &segments[term]))
+            })
             .collect();
 
-        info!("Search results: {result:#?}");
+        let postings = |term: &str| gathered.get(term).filter(|postings| !postings.is_empty());
 
-        result
+        let matches = query.evaluate(&postings, documents.ids());
+
+        let terms: Vec<(&LivePostings, usize)> = query
+            .scoring_terms()
+            .into_iter()
+            .filter_map(postings)
+            .map(|postings| (postings, postings.len()))
+            .collect();
+
+        let hits = matches
+            .into_iter()
+            .map(|id| {
+                let length = documents.get(&id).map_or(0, |document| document.length);
+                let score = terms
+                    .iter()
+                    .filter_map(|(postings, document_frequency)| {
+                        // Postings loaded from files without positions still count once
+                        let term_frequency = postings.get(id)?.len().max(1);
+                        Some(bm25.score(term_frequency, *document_frequency, length))
+                    })
+                    .sum();
+
+                SearchHit {
+                    id,
+                    score,
+                    snippet: None,
+                }
+            })
+            .collect();
+
+        // Terms without any postings are probably misspelled
+        let suggestions = typed
+            .into_iter()
+            .filter(|term| postings(term).is_none())
+            .map(|term| {
+                let suggestions = self.suggest(&term);
+                (term, suggestions)
+            })
+            .filter(|(_, suggestions)| !suggestions.is_empty())
+            .collect();
+
+        let mut results = ranking::rank(hits, options.offset, options.limit);
+        results.suggestions = suggestions;
+
+        if options.snippets {
+            let paths: Vec<Option<String>> = results
+                .hits
+                .iter()
+                .take(MAX_SNIPPETS)
+                .map(|hit| documents.get(&hit.id).map(|document| document.path.clone()))
+                .collect();
+
+            // Files are re-read without blocking indexing
+            drop(documents);
+
+            let terms: HashSet<&str> = query.scoring_terms().into_iter().collect();
+            for (hit, path) in results.hits.iter_mut().zip(paths) {
+                hit.snippet = path.and_then(|path| match std::fs::read_to_string(&path) {
+                    Ok(content) => Some(snippet::snippet(&content, &self.analyzer, &terms)),
+                    Err(e) => {
+                        warn!("Failed to read document {path} for its snippet: {e}");
+                        None
+                    }
+                });
+            }
+        }
+
+        info!("Search results: {results:#?}");
+
+        Ok(results)
+    }
+
+    /// Terms of the index within [`MAX_EDIT_DISTANCE`] of `term`, the closest first and
+    /// the ones in more documents first among equally close ones.
+    fn suggest(&self, term: &str) -> Vec<String> {
+        let pattern = Pattern::fuzzy(term, MAX_EDIT_DISTANCE);
+
+        let mut candidates: Vec<(u8, Reverse<usize>, String)> = self
+            .vocabulary
+            .matching(&pattern, MAX_EXPANSIONS)
+            .into_iter()
+            .filter_map(|candidate| {
+                let distance = pattern.edit_distance(&candidate)?;
+                let frequency = self.vocabulary.document_frequency(&candidate);
+
+                Some((distance, Reverse(frequency), candidate))
+            })
+            .collect();
+
+        candidates.sort();
+
+        candidates
+            .into_iter()
+            .take(SUGGESTIONS)
+            .map(|(_, _, candidate)| candidate)
+            .collect()
+    }
+
+    /// The `limit` indexed terms starting with `prefix` that the most documents contain,
+    /// with their document frequencies. The prefix is normalized like query terms, but
+    /// not stemmed, as it is usually an incomplete word.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        self.vocabulary
+            .complete(&self.analyzer.normalize(prefix), limit)
     }
 
     pub fn delete_document(&self, document_id: u64) -> std::io::Result<()> {
-        {
-            let mut documents = self.documents.write().unwrap();
+        if let Some(paths) = self.unregister_document(document_id)? {
+            self.purge_document(document_id, &paths)?;
+        }
 
-            if let Some(path) = documents.remove(&document_id) {
-                std::fs::remove_file(&path)?;
-            }
+        Ok(())
+    }
+
+    /// Logs the deletion of a document and removes it from the document table, so
+    /// that it disappears from searches right away.
+    ///
+    /// Returns the paths of the document's files, which are two while an update is
+    /// waiting to be indexed, or `None` if the document does not exist. The files are
+    /// left for [`InvertedIndex::purge_document`], while the postings are dropped the
+    /// next time their segment is merged.
+    pub fn unregister_document(&self, document_id: u64) -> std::io::Result<Option<Vec<String>>> {
+        let mut log = self.log.lock().unwrap();
+
+        if !self.document_exists(document_id) {
+            return Ok(None);
         }
 
-        {
-            let mut index = self.index.write().unwrap();
+        log.append(&Operation::Delete { id: document_id })?;
 
-            for ids in index.values_mut() {
-                ids.retain(|&id| id != document_id);
-            }
+        let paths = self.remove_document(document_id);
+
+        Ok(Some(paths))
+    }
+
+    /// Returns the paths of all files of the document.
+    fn remove_document(&self, document_id: u64) -> Vec<String> {
+        let mut pending = self.pending.write().unwrap();
+        let mut documents = self.documents.write().unwrap();
+
+        let pending_path = pending.remove(&document_id).map(|pending| pending.path);
+        let document = documents.remove(document_id);
+
+        if let Some(document) = &document {
+            let terms = self.document_terms(document_id, document);
+            self.vocabulary.remove(terms.iter().map(String::as_str));
+            self.forward_index.write().unwrap().remove(&document_id);
+            self.delete_version(document_id, document.generation);
+        }
+
+        let paths: Vec<String> = pending_path
+            .into_iter()
+            .chain(document.map(|document| document.path))
+            .collect();
+        self.removing.lock().unwrap().extend(paths.iter().cloned());
+
+        paths
+    }
+
+    /// Removes the files of a document that has been unregistered.
+    pub fn purge_document(&self, document_id: u64, paths: &[String]) -> std::io::Result<()> {
+        for path in paths {
+            self.remove_file(path)?;
         }
 
         info!("Document deleted: {document_id}");
@@ -176,12 +1040,154 @@ impl InvertedIndex {
         Ok(())
     }
 
+    /// Keeps [`InvertedIndex::reconcile_uploads`] from taking the file at `path` for a
+    /// lost upload while the returned guard lives. Taken before the file is created and
+    /// dropped once it has been registered.
+    pub fn start_upload(&self, path: String) -> Upload<'_> {
+        self.uploading.lock().unwrap().insert(path.clone());
+
+        Upload { index: self, path }
+    }
+
+    /// Makes the documents agree with the files in `uploads_dir`, after the state
+    /// file was lost or restored from an older backup.
+    ///
+    /// Every file no document refers to is registered as a new document, with IDs
+    /// following the highest one that survived, and documents whose files are all
+    /// gone are deleted. The new documents still have to be indexed.
+    pub fn reconcile_uploads(&self, uploads_dir: &str) -> std::io::Result<Reconciled> {
+        // Uploads are only kept from starting or being registered, not from being
+        // received, while the directory is scanned
+        let uploading = self.uploading.lock().unwrap();
+
+        // ID -> Whether any file of the document still exists
+        let mut found: HashMap<u64, bool> = HashMap::new();
+        let known: HashSet<String> = {
+            let pending = self.pending.read().unwrap();
+            let documents = self.documents.read().unwrap();
+            let removing = self.removing.lock().unwrap();
+
+            let paths = pending
+                .iter()
+                .map(|(&id, pending)| (id, &pending.path))
+                .chain(documents.iter().map(|(&id, document)| (id, &document.path)));
+            for (id, path) in paths.clone() {
+                *found.entry(id).or_default() |= Path::new(path).exists();
+            }
+
+            paths
+                .map(|(_, path)| path.clone())
+                .chain(removing.iter().cloned())
+                .chain(uploading.iter().cloned())
+                .collect()
+        };
+
+        let mut orphans = Vec::new();
+        for entry in std::fs::read_dir(uploads_dir)? {
+            let entry = entry?;
+            let file = entry.metadata()?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                warn!(
+                    "Skipping upload with a non UTF-8 name: {:?}",
+                    entry.file_name()
+                );
+                continue;
+            };
+
+            let path = format!("{uploads_dir}/{name}");
+            if file.is_file() && !known.contains(&path) {
+                orphans.push((Metadata::recovered(&file), path));
+            }
+        }
+        orphans.sort_by(|(a, a_path), (b, b_path)| {
+            (a.uploaded_at, a_path).cmp(&(b.uploaded_at, b_path))
+        });
+
+        let mut reconciled = Reconciled::default();
+
+        for (metadata, path) in orphans {
+            info!("Registering file without a document: {path}");
+            reconciled
+                .added
+                .push(self.register_document(path, metadata)?);
+        }
+
+        let mut lost: Vec<u64> = found
+            .into_iter()
+            .filter(|&(_, found)| !found)
+            .map(|(id, _)| id)
+            .collect();
+        lost.sort();
+
+        for id in lost {
+            // The document may have been deleted in the meantime
+            if let Some(paths) = self.unregister_document(id)? {
+                warn!("Deleting document {id}, its files are gone");
+                self.purge_document(id, &paths)?;
+                reconciled.removed.push(id);
+            }
+        }
+
+        Ok(reconciled)
+    }
+
     pub fn document_exists(&self, document_id: u64) -> bool {
         self.documents.read().unwrap().contains_key(&document_id)
+            || self.pending.read().unwrap().contains_key(&document_id)
     }
 
     pub fn get_document_path(&self, document_id: u64) -> Option<String> {
-        self.documents.read().unwrap().get(&document_id).cloned()
+        self.get_document_file(document_id).map(|(path, _)| path)
+    }
+
+    pub fn get_document_metadata(&self, document_id: u64) -> Option<Metadata> {
+        self.get_document_file(document_id)
+            .map(|(_, metadata)| metadata)
+    }
+
+    /// Path and metadata of the version of a document that is served to clients,
+    /// which is the old one while an update is being indexed.
+    pub fn get_document_file(&self, document_id: u64) -> Option<(String, Metadata)> {
+        // Both are locked at once, so that a document being indexed is found in one
+        let pending = self.pending.read().unwrap();
+        let documents = self.documents.read().unwrap();
+
+        match documents.get(&document_id) {
+            Some(document) => Some((document.path.clone(), document.metadata.clone())),
+            None => pending
+                .get(&document_id)
+                .map(|pending| (pending.path.clone(), pending.metadata.clone())),
+        }
+    }
+
+    /// A page of all documents, including those waiting to be indexed, in the order
+    /// given by `options`.
+    pub fn list_documents(&self, options: &ListOptions) -> ListResult<Page> {
+        let documents = {
+            let pending = self.pending.read().unwrap();
+            let documents = self.documents.read().unwrap();
+
+            let indexed = documents.iter().map(|(&id, document)| DocumentInfo {
+                id,
+                path: document.path.clone(),
+                indexed: true,
+                metadata: document.metadata.clone(),
+            });
+            // The new version of an updated document is not served until it is indexed
+            let waiting = pending
+                .iter()
+                .filter(|(id, _)| !documents.contains_key(id))
+                .map(|(&id, document)| DocumentInfo {
+                    id,
+                    path: document.path.clone(),
+                    indexed: false,
+                    metadata: document.metadata.clone(),
+                });
+
+            indexed.chain(waiting).collect()
+        };
+
+        listing::paginate(documents, options)
     }
 
     pub fn get_document_count(&self) -> usize {
@@ -189,8 +1195,46 @@ impl InvertedIndex {
     }
 }
 
+/// Whether the version of a document stored with `generation` is the current one.
+fn is_live(documents: &HashMap<u64, Document>, id: u64, generation: u64) -> bool {
+    documents
+        .get(&id)
+        .is_some_and(|document| document.generation == generation)
+}
+
+/// Postings of `term` in the live documents, gathered from its postings in the mapped
+/// state file, if any, and from the segments of the term's shard. Documents marked as
+/// deleted are subtracted from each one without looking at the document table.
+fn live_postings<'a>(
+    term: &str,
+    base: Option<(&'a Postings, &MappedIndex)>,
+    segments: &'a [Arc<Segment>],
+) -> LivePostings<'a> {
+    let mut postings = LivePostings::new();
+
+    if let Some((base_postings, base)) = base {
+        postings.add(base_postings, &base.deleted());
+    }
+
+    for segment in segments {
+        if let Some(segment_postings) = segment.postings(term) {
+            postings.add(segment_postings, &segment.deleted());
+        }
+    }
+
+    postings
+}
+
+impl Default for InvertedIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for InvertedIndex {
     fn drop(&mut self) {
-        self.save();
+        if let Err(e) = self.save() {
+            error!("Failed to save index: {e}");
+        }
     }
 }
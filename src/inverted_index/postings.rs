@@ -0,0 +1,200 @@
+use super::DocumentSet;
+use std::collections::BTreeMap;
+
+/// The documents containing a term, as a bitmap, with the positions of the term in
+/// every one of them stored beside it in the order of the IDs.
+///
+/// Query evaluation works on the bitmap alone. Positions are only looked up for the
+/// documents that are left, by the rank of their ID in the bitmap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Postings {
+    documents: DocumentSet,
+    // Start of the positions of every document in `positions`, in the order of the
+    // IDs, followed by the end of the last one
+    offsets: Vec<u32>,
+    // Sorted positions of the term within every document, one run after another
+    positions: Vec<u32>,
+}
+
+impl Postings {
+    pub fn new() -> Self {
+        Postings::default()
+    }
+
+    /// Appends a document, which must have a greater ID than every document before.
+    ///
+    /// Returns `false` and leaves the postings unchanged otherwise.
+    pub fn push(&mut self, id: u64, positions: &[u32]) -> bool {
+        // The treemap only checks the order of IDs with the same upper 32 bits
+        if self.documents.max().is_some_and(|max| id <= max) {
+            return false;
+        }
+        self.documents.push(id);
+
+        if self.offsets.is_empty() {
+            self.offsets.push(0);
+        }
+        self.positions.extend_from_slice(positions);
+        self.offsets.push(self.positions.len() as u32);
+
+        true
+    }
+
+    pub fn documents(&self) -> &DocumentSet {
+        &self.documents
+    }
+
+    /// Number of documents.
+    pub fn len(&self) -> usize {
+        self.documents.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Positions of the term within a document, or `None` if it does not contain it.
+    pub fn get(&self, id: u64) -> Option<&[u32]> {
+        if !self.documents.contains(id) {
+            return None;
+        }
+
+        Some(self.positions_at(self.documents.rank(id) as usize - 1))
+    }
+
+    /// Every document in ascending order of IDs, with its positions.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u32])> {
+        self.documents
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (id, self.positions_at(index)))
+    }
+
+    fn positions_at(&self, index: usize) -> &[u32] {
+        &self.positions[self.offsets[index] as usize..self.offsets[index + 1] as usize]
+    }
+}
+
+impl FromIterator<(u64, Vec<u32>)> for Postings {
+    /// Collects documents in any order. Of several entries for the same ID, the last
+    /// one is kept.
+    fn from_iter<I: IntoIterator<Item = (u64, Vec<u32>)>>(entries: I) -> Self {
+        let sorted: BTreeMap<u64, Vec<u32>> = entries.into_iter().collect();
+
+        let mut postings = Postings::new();
+        for (id, positions) in sorted {
+            postings.push(id, &positions);
+        }
+
+        postings
+    }
+}
+
+impl<const N: usize> From<[(u64, Vec<u32>); N]> for Postings {
+    fn from(entries: [(u64, Vec<u32>); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+/// The postings of a term in the live documents, which may be spread over the mapped
+/// state file and several segments. Each part only counts for its documents in the
+/// live set it was added with, and no document is live in more than one part.
+#[derive(Debug, Default)]
+pub struct LivePostings<'a> {
+    documents: DocumentSet,
+    parts: Vec<(&'a Postings, DocumentSet)>,
+}
+
+impl<'a> LivePostings<'a> {
+    pub fn new() -> Self {
+        LivePostings::default()
+    }
+
+    /// Adds the documents of `postings` that are not in `deleted`.
+    pub fn add(&mut self, postings: &'a Postings, deleted: &DocumentSet) {
+        let live = postings.documents() - deleted;
+
+        if !live.is_empty() {
+            self.documents |= &live;
+            self.parts.push((postings, live));
+        }
+    }
+
+    pub fn documents(&self) -> &DocumentSet {
+        &self.documents
+    }
+
+    /// Number of live documents.
+    pub fn len(&self) -> usize {
+        self.documents.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Positions of the term within a live document, or `None` if it does not
+    /// contain it.
+    pub fn get(&self, id: u64) -> Option<&'a [u32]> {
+        self.parts
+            .iter()
+            .find(|(_, live)| live.contains(id))
+            .and_then(|(postings, _)| postings.get(id))
+    }
+}
+
+impl<'a> From<&'a Postings> for LivePostings<'a> {
+    /// All documents of `postings`.
+    fn from(postings: &'a Postings) -> Self {
+        let mut live = LivePostings::new();
+        live.add(postings, &DocumentSet::new());
+        live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_postings() {
+        let postings = Postings::from([(300, vec![0]), (3, vec![0, 2, 200]), (7, vec![])]);
+
+        assert_eq!(postings.len(), 3);
+        assert_eq!(postings.get(3), Some(&[0, 2, 200][..]));
+        assert_eq!(postings.get(7), Some(&[][..]));
+        assert_eq!(postings.get(300), Some(&[0][..]));
+        assert_eq!(postings.get(4), None);
+        assert_eq!(
+            postings.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            [3, 7, 300]
+        );
+
+        let mut appended = Postings::new();
+        assert!(appended.push(5, &[1]));
+        assert!(!appended.push(5, &[2]));
+        assert!(!appended.push(4, &[2]));
+        assert!(appended.push(1 << 32, &[3]));
+        assert!(!appended.push(6, &[4]));
+        assert_eq!(appended, Postings::from([(5, vec![1]), (1 << 32, vec![3])]));
+    }
+
+    #[test]
+    fn test_live_postings() {
+        // Document 2 was replaced, its new version lives in the second part
+        let old = Postings::from([(1, vec![0]), (2, vec![1]), (3, vec![2])]);
+        let new = Postings::from([(2, vec![5])]);
+
+        let empty = Postings::new();
+
+        let mut live = LivePostings::new();
+        live.add(&old, &DocumentSet::from_iter([2, 3]));
+        live.add(&new, &DocumentSet::new());
+        live.add(&empty, &DocumentSet::new());
+
+        assert_eq!(*live.documents(), DocumentSet::from_iter([1, 2]));
+        assert_eq!(live.get(1), Some(&[0][..]));
+        assert_eq!(live.get(2), Some(&[5][..]));
+        assert_eq!(live.get(3), None);
+    }
+}
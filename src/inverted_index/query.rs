@@ -0,0 +1,1051 @@
+use super::analysis::Analyzer;
+use super::postings::LivePostings;
+use super::DocumentSet;
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA};
+use regex::Regex;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Maximum number of terms a single wildcard, regex or fuzzy term may expand to.
+pub const MAX_EXPANSIONS: usize = 1024;
+
+/// Maximum edit distance of a fuzzy term, also used for `term~` without a distance.
+pub const MAX_EDIT_DISTANCE: u8 = 2;
+
+lazy_static::lazy_static! {
+    // Creating a builder takes a few milliseconds, building an automaton with it only
+    // depends on the length of the term
+    static ref LEVENSHTEIN: Vec<LevenshteinAutomatonBuilder> = (0..=MAX_EDIT_DISTANCE)
+        .map(|distance| LevenshteinAutomatonBuilder::new(distance, true))
+        .collect();
+}
+
+/// Operator used between two clauses that are not joined by an explicit `AND` or `OR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Operator {
+    #[default]
+    Or,
+    And,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub default_operator: Operator,
+    /// Number of ranked hits to skip, for paging through the results.
+    pub offset: usize,
+    /// Maximum number of ranked hits to return, all of them if `None`.
+    pub limit: Option<usize>,
+    /// Whether the first hits, at most 20, come with a highlighted snippet of the
+    /// document.
+    pub snippets: bool,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QueryError {
+    #[error("Query is empty")]
+    Empty,
+
+    #[error("Unexpected '{0}' at position {1}")]
+    UnexpectedToken(String, usize),
+
+    #[error("Unexpected end of query, expected {0}")]
+    UnexpectedEnd(&'static str),
+
+    #[error("Missing closing parenthesis for '(' at position {0}")]
+    UnclosedParenthesis(usize),
+
+    #[error("Missing closing quote for '\"' at position {0}")]
+    UnclosedQuote(usize),
+
+    #[error("NEAR/{0} at position {1} must be placed between two single terms")]
+    InvalidNearOperand(u32, usize),
+
+    #[error("Query contains no searchable terms")]
+    NoSearchableTerms,
+
+    #[error("Missing closing '/' for the regex at position {0}")]
+    UnclosedRegex(usize),
+
+    #[error("Invalid regex /{0}/: {1}")]
+    InvalidRegex(String, String),
+
+    #[error("{0} matches more than {1} terms, try a longer prefix")]
+    TooManyExpansions(String, usize),
+
+    #[error(
+        "Edit distance {0} at position {1} is too large, the maximum is {max}",
+        max = MAX_EDIT_DISTANCE
+    )]
+    InvalidEditDistance(u32, usize),
+}
+
+pub type QueryResult<T> = std::result::Result<T, QueryError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(String),
+    /// Terms that must appear in order, each at the given offset from the first one.
+    /// Offsets are usually consecutive, but keep gaps left by removed stop words.
+    Phrase(Vec<(String, u32)>),
+    /// Two terms that must appear at most the given number of positions apart.
+    Near(String, String, u32),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    /// A wildcard, regex or fuzzy term, replaced by the terms it matches with
+    /// [`Query::expand`].
+    Pattern(Pattern),
+}
+
+/// Terms written as a wildcard (`paral*`, `colou?r`), a regex (`/colou?r/`) or a fuzzy
+/// term (`colour~1`), which are looked up in the vocabulary of the index.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// Text every matching term starts with, which narrows down the lookup
+    prefix: String,
+    matcher: Matcher,
+    // As written in the query, for error messages
+    text: String,
+}
+
+/// What matching terms have to look like besides starting with the prefix.
+#[derive(Clone)]
+enum Matcher {
+    Any,
+    /// Matches whole terms
+    Regex(Regex),
+    /// Accepts the terms within an edit distance
+    Fuzzy(Arc<DFA>),
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Any => f.write_str("Any"),
+            Matcher::Regex(regex) => f.debug_tuple("Regex").field(regex).finish(),
+            Matcher::Fuzzy(_) => f.write_str("Fuzzy"),
+        }
+    }
+}
+
+impl Pattern {
+    /// A pattern where `*` stands for any number of characters and `?` for exactly one.
+    fn wildcard(text: &str) -> Self {
+        let literal = text.find(['*', '?']).unwrap_or(text.len());
+        let prefix = text[..literal].to_string();
+
+        let matcher = if &text[literal..] == "*" {
+            Matcher::Any
+        } else {
+            let pattern: String = text
+                .chars()
+                .map(|c| match c {
+                    '*' => ".*".to_string(),
+                    '?' => ".".to_string(),
+                    c => regex::escape(c.encode_utf8(&mut [0; 4])),
+                })
+                .collect();
+
+            Matcher::Regex(
+                Regex::new(&format!("^(?:{pattern})$")).expect("wildcards are valid regexes"),
+            )
+        };
+
+        Pattern {
+            prefix,
+            matcher,
+            text: text.to_string(),
+        }
+    }
+
+    fn regex(text: &str) -> QueryResult<Self> {
+        let regex = Regex::new(&format!("^(?:{text})$")).map_err(|e| {
+            // The error of the regex as written points at the right position
+            let e = Regex::new(text).err().unwrap_or(e);
+            QueryError::InvalidRegex(text.to_string(), e.to_string())
+        })?;
+
+        Ok(Pattern {
+            prefix: String::new(),
+            matcher: Matcher::Regex(regex),
+            text: format!("/{text}/"),
+        })
+    }
+
+    /// Terms at most `distance` edits away from `term`, where an edit inserts, removes
+    /// or replaces a character, or swaps two adjacent ones.
+    pub fn fuzzy(term: &str, distance: u8) -> Self {
+        let automaton = LEVENSHTEIN[distance as usize].build_dfa(term);
+
+        Pattern {
+            prefix: String::new(),
+            matcher: Matcher::Fuzzy(Arc::new(automaton)),
+            text: format!("{term}~{distance}"),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The automaton of a fuzzy term, which is run over the vocabulary directly.
+    pub fn automaton(&self) -> Option<&DFA> {
+        match &self.matcher {
+            Matcher::Fuzzy(automaton) => Some(automaton),
+            _ => None,
+        }
+    }
+
+    /// Number of edits between `term` and a fuzzy term, `None` if `term` is too far
+    /// away or the pattern is not fuzzy.
+    pub fn edit_distance(&self, term: &str) -> Option<u8> {
+        match self.automaton()?.eval(term) {
+            Distance::Exact(distance) => Some(distance),
+            Distance::AtLeast(_) => None,
+        }
+    }
+
+    pub fn matches(&self, term: &str) -> bool {
+        term.starts_with(&self.prefix)
+            && match &self.matcher {
+                Matcher::Any => true,
+                Matcher::Regex(regex) => regex.is_match(term),
+                Matcher::Fuzzy(automaton) => matches!(automaton.eval(term), Distance::Exact(_)),
+            }
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Wildcard(String),
+    Regex(String),
+    Fuzzy(String, u32),
+    Near(u32),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Token {
+    fn starts_clause(&self) -> bool {
+        matches!(
+            self,
+            Token::Word(_)
+                | Token::Phrase(_)
+                | Token::Wildcard(_)
+                | Token::Regex(_)
+                | Token::Fuzzy(..)
+                | Token::Not
+                | Token::Open
+        )
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Token::Word(word) | Token::Wildcard(word) => word.clone(),
+            Token::Phrase(phrase) => format!("\"{phrase}\""),
+            Token::Regex(regex) => format!("/{regex}/"),
+            Token::Fuzzy(word, distance) => format!("{word}~{distance}"),
+            Token::Near(distance) => format!("NEAR/{distance}"),
+            Token::And => "AND".to_string(),
+            Token::Or => "OR".to_string(),
+            Token::Not => "NOT".to_string(),
+            Token::Open => "(".to_string(),
+            Token::Close => ")".to_string(),
+        }
+    }
+}
+
+/// Splits the query into words, wildcards, fuzzy `words~2`, quoted phrases,
+/// `/regexes/`, parentheses and the upper-case `AND`, `OR`, `NOT` and `NEAR/k`
+/// operators. Each token is paired with its character position for error messages.
+fn lex(query: &str) -> QueryResult<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut word_start = 0;
+    let mut phrase_start = None;
+    let mut regex_start = None;
+
+    for (position, c) in query.chars().enumerate() {
+        if let Some(start) = phrase_start {
+            if c == '"' {
+                tokens.push((Token::Phrase(std::mem::take(&mut word)), start));
+                phrase_start = None;
+            } else {
+                word.push(c);
+            }
+            continue;
+        }
+
+        if let Some(start) = regex_start {
+            if c == '/' && word.ends_with('\\') {
+                // An escaped slash is part of the regex
+                word.pop();
+                word.push(c);
+            } else if c == '/' {
+                tokens.push((Token::Regex(std::mem::take(&mut word)), start));
+                regex_start = None;
+            } else {
+                word.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                push_word(&mut word, word_start, &mut tokens);
+                phrase_start = Some(position);
+            }
+            '/' if word.is_empty() => regex_start = Some(position),
+            '(' | ')' => {
+                push_word(&mut word, word_start, &mut tokens);
+                let token = if c == '(' { Token::Open } else { Token::Close };
+                tokens.push((token, position));
+            }
+            c if c.is_whitespace() => push_word(&mut word, word_start, &mut tokens),
+            c => {
+                if word.is_empty() {
+                    word_start = position;
+                }
+                word.push(c);
+            }
+        }
+    }
+
+    if let Some(start) = phrase_start {
+        return Err(QueryError::UnclosedQuote(start));
+    }
+
+    if let Some(start) = regex_start {
+        return Err(QueryError::UnclosedRegex(start));
+    }
+
+    push_word(&mut word, word_start, &mut tokens);
+
+    Ok(tokens)
+}
+
+fn push_word(word: &mut String, start: usize, tokens: &mut Vec<(Token, usize)>) {
+    if word.is_empty() {
+        return;
+    }
+
+    let near_distance = word
+        .strip_prefix("NEAR/")
+        .and_then(|distance| distance.parse().ok());
+
+    // `word~1`, or `word~` for the maximum distance
+    let fuzzy = word.rsplit_once('~').and_then(|(word, distance)| {
+        let distance = match distance {
+            "" => MAX_EDIT_DISTANCE as u32,
+            distance => distance.parse().ok()?,
+        };
+
+        word.chars()
+            .any(char::is_alphanumeric)
+            .then(|| (word.to_string(), distance))
+    });
+
+    let token = match (word.as_str(), near_distance, fuzzy) {
+        (_, Some(distance), _) => Token::Near(distance),
+        ("AND", _, _) => Token::And,
+        ("OR", _, _) => Token::Or,
+        ("NOT", _, _) => Token::Not,
+        (_, _, Some((word, distance))) => Token::Fuzzy(word, distance),
+        // Punctuation such as `?!` is dropped like in any other word, instead of
+        // matching every short term
+        _ if word.contains(['*', '?']) && word.chars().any(char::is_alphanumeric) => {
+            Token::Wildcard(word.clone())
+        }
+        _ => Token::Word(word.clone()),
+    };
+
+    tokens.push((token, start));
+    word.clear();
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    default_operator: Operator,
+    analyzer: &'a Analyzer,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Consumes the next token if it is `operator`, or reports an implicit operator
+    /// when the next token starts a new clause and `operator` is the default one.
+    fn take_operator(&mut self, operator: Operator) -> bool {
+        let explicit = match operator {
+            Operator::And => Token::And,
+            Operator::Or => Token::Or,
+        };
+
+        match self.peek() {
+            Some(token) if *token == explicit => {
+                self.position += 1;
+                true
+            }
+            Some(token) => token.starts_clause() && self.default_operator == operator,
+            None => false,
+        }
+    }
+
+    // or_expr := and_expr (OR and_expr)*
+    fn parse_or(&mut self) -> QueryResult<Query> {
+        let mut clauses = vec![self.parse_and()?];
+
+        while self.take_operator(Operator::Or) {
+            clauses.push(self.parse_and()?);
+        }
+
+        Ok(Self::combine(clauses, Query::Or))
+    }
+
+    // and_expr := not_expr (AND not_expr)*
+    fn parse_and(&mut self) -> QueryResult<Query> {
+        let mut clauses = vec![self.parse_not()?];
+
+        while self.take_operator(Operator::And) {
+            clauses.push(self.parse_not()?);
+        }
+
+        Ok(Self::combine(clauses, Query::And))
+    }
+
+    // not_expr := NOT not_expr | near_expr
+    fn parse_not(&mut self) -> QueryResult<Query> {
+        if self.peek() == Some(&Token::Not) {
+            self.position += 1;
+
+            let query = self.parse_not()?;
+            if query.is_empty() {
+                return Ok(query);
+            }
+
+            return Ok(Query::Not(Box::new(query)));
+        }
+
+        self.parse_near()
+    }
+
+    // near_expr := primary (NEAR/k primary)?
+    fn parse_near(&mut self) -> QueryResult<Query> {
+        let left = self.parse_primary()?;
+
+        let Some(&Token::Near(distance)) = self.peek() else {
+            return Ok(left);
+        };
+        let (_, position) = self.next().unwrap();
+
+        let right = self.parse_primary()?;
+
+        match (left, right) {
+            (Query::Term(left), Query::Term(right)) => Ok(Query::Near(left, right, distance)),
+            _ => Err(QueryError::InvalidNearOperand(distance, position)),
+        }
+    }
+
+    // primary := ( or_expr ) | "phrase" | /regex/ | wildcard | word~distance | word
+    fn parse_primary(&mut self) -> QueryResult<Query> {
+        match self.next() {
+            Some((Token::Open, position)) => {
+                let query = self.parse_or()?;
+
+                match self.next() {
+                    Some((Token::Close, _)) => Ok(query),
+                    Some((token, position)) => {
+                        Err(QueryError::UnexpectedToken(token.text(), position))
+                    }
+                    None => Err(QueryError::UnclosedParenthesis(position)),
+                }
+            }
+            Some((Token::Word(word), _)) | Some((Token::Phrase(word), _)) => {
+                Ok(self.phrase_query(&word))
+            }
+            // Wildcards are normalized but not stemmed, the stem of a word with a
+            // wildcard in it is rarely a prefix of the stems it should match
+            Some((Token::Wildcard(wildcard), _)) => Ok(Query::Pattern(Pattern::wildcard(
+                &self.analyzer.normalize(&wildcard),
+            ))),
+            Some((Token::Regex(regex), _)) => Ok(Query::Pattern(Pattern::regex(&regex)?)),
+            Some((Token::Fuzzy(word, distance), position)) => {
+                self.fuzzy_query(&word, distance, position)
+            }
+            Some((token, position)) => Err(QueryError::UnexpectedToken(token.text(), position)),
+            None => Err(QueryError::UnexpectedEnd("a search term")),
+        }
+    }
+
+    /// Both quoted phrases and single words (e.g. `day!`) may consist of several
+    /// index terms, which then have to appear next to each other. Words the analyzer
+    /// drops entirely, such as stop words, produce an empty query.
+    fn phrase_query(&self, text: &str) -> Query {
+        let mut tokens = self.analyzer.analyze(text);
+
+        match tokens.len() {
+            0 => Query::empty(),
+            1 => Query::Term(tokens.remove(0).text),
+            _ => {
+                let first = tokens[0].position;
+                let terms = tokens
+                    .into_iter()
+                    .map(|token| (token.text, token.position - first))
+                    .collect();
+
+                Query::Phrase(terms)
+            }
+        }
+    }
+
+    /// Fuzzy words are analyzed like any other word first, so that with the English
+    /// analyzer `runing~1` is compared to the stems in the index. Only words that are
+    /// a single term become fuzzy.
+    fn fuzzy_query(&self, word: &str, distance: u32, position: usize) -> QueryResult<Query> {
+        let distance = u8::try_from(distance)
+            .ok()
+            .filter(|&distance| distance <= MAX_EDIT_DISTANCE)
+            .ok_or(QueryError::InvalidEditDistance(distance, position))?;
+
+        match self.phrase_query(word) {
+            Query::Term(term) if distance > 0 => Ok(Query::Pattern(Pattern {
+                text: format!("{word}~{distance}"),
+                ..Pattern::fuzzy(&term, distance)
+            })),
+            query => Ok(query),
+        }
+    }
+
+    /// Empty clauses are left out, so `the AND rust` searches for `rust` alone when
+    /// `the` is a stop word.
+    fn combine(mut clauses: Vec<Query>, operator: fn(Vec<Query>) -> Query) -> Query {
+        clauses.retain(|clause| !clause.is_empty());
+
+        match clauses.len() {
+            0 => Query::empty(),
+            1 => clauses.remove(0),
+            _ => operator(clauses),
+        }
+    }
+}
+
+impl Query {
+    fn empty() -> Query {
+        Query::And(Vec::new())
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Query::And(clauses) if clauses.is_empty())
+    }
+
+    pub fn parse(
+        query: &str,
+        default_operator: Operator,
+        analyzer: &Analyzer,
+    ) -> QueryResult<Query> {
+        let tokens = lex(query)?;
+
+        if tokens.is_empty() {
+            return Err(QueryError::Empty);
+        }
+
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            default_operator,
+            analyzer,
+        };
+
+        let query = parser.parse_or()?;
+
+        match parser.next() {
+            Some((token, position)) => Err(QueryError::UnexpectedToken(token.text(), position)),
+            None if query.is_empty() => Err(QueryError::NoSearchableTerms),
+            None => Ok(query),
+        }
+    }
+
+    /// Terms that contribute to the relevance score, i.e. all terms outside of `NOT`.
+    pub fn scoring_terms(&self) -> Vec<&str> {
+        match self {
+            Query::Term(term) => vec![term],
+            Query::Phrase(terms) => terms.iter().map(|(term, _)| term.as_str()).collect(),
+            Query::Near(left, right, _) => vec![left, right],
+            Query::And(clauses) | Query::Or(clauses) => {
+                clauses.iter().flat_map(Query::scoring_terms).collect()
+            }
+            Query::Not(_) | Query::Pattern(_) => Vec::new(),
+        }
+    }
+
+    /// Replaces every pattern with the `OR` of the terms it matches. `lookup` returns
+    /// the terms of the index matching a pattern, at most the given number of them.
+    ///
+    /// Returns an error if a pattern matches more than [`MAX_EXPANSIONS`] terms.
+    pub fn expand<F>(self, lookup: &F) -> QueryResult<Query>
+    where
+        F: Fn(&Pattern, usize) -> Vec<String>,
+    {
+        let expand_all = |clauses: Vec<Query>| -> QueryResult<Vec<Query>> {
+            clauses
+                .into_iter()
+                .map(|clause| clause.expand(lookup))
+                .collect()
+        };
+
+        match self {
+            Query::Pattern(pattern) => {
+                let mut terms = lookup(&pattern, MAX_EXPANSIONS + 1);
+
+                match terms.len() {
+                    1 => Ok(Query::Term(terms.remove(0))),
+                    count if count > MAX_EXPANSIONS => {
+                        Err(QueryError::TooManyExpansions(pattern.text, MAX_EXPANSIONS))
+                    }
+                    // No terms at all give an `OR` without clauses, which matches nothing
+                    _ => Ok(Query::Or(terms.into_iter().map(Query::Term).collect())),
+                }
+            }
+            Query::And(clauses) => Ok(Query::And(expand_all(clauses)?)),
+            Query::Or(clauses) => Ok(Query::Or(expand_all(clauses)?)),
+            Query::Not(clause) => Ok(Query::Not(Box::new(clause.expand(lookup)?))),
+            query => Ok(query),
+        }
+    }
+
+    /// Every term the query looks up, including the ones under `NOT`.
+    pub fn terms(&self) -> Vec<&str> {
+        match self {
+            Query::And(clauses) | Query::Or(clauses) => {
+                clauses.iter().flat_map(Query::terms).collect()
+            }
+            Query::Not(clause) => clause.terms(),
+            _ => self.scoring_terms(),
+        }
+    }
+
+    /// Evaluates the query with `postings` looking up the positional postings of a
+    /// term and `all_documents` being the universe that `NOT` subtracts from.
+    pub fn evaluate<'a, 'b: 'a, F>(&self, postings: &F, all_documents: &DocumentSet) -> DocumentSet
+    where
+        F: Fn(&str) -> Option<&'a LivePostings<'b>>,
+    {
+        match self {
+            Query::Term(term) => postings(term)
+                .map(|postings| postings.documents().clone())
+                .unwrap_or_default(),
+            Query::Phrase(terms) => {
+                let Some(postings) = terms
+                    .iter()
+                    .map(|(term, offset)| Some((postings(term)?, *offset)))
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    return DocumentSet::new();
+                };
+
+                phrase_matches(&postings)
+            }
+            Query::Near(left, right, distance) => match (postings(left), postings(right)) {
+                (Some(left), Some(right)) => near_matches(left, right, *distance),
+                _ => DocumentSet::new(),
+            },
+            Query::Or(clauses) => clauses
+                .iter()
+                .map(|clause| clause.evaluate(postings, all_documents))
+                .fold(DocumentSet::new(), |result, ids| result | ids),
+            Query::Not(clause) => all_documents - clause.evaluate(postings, all_documents),
+            // Patterns are expanded into terms before evaluation
+            Query::Pattern(_) => DocumentSet::new(),
+            Query::And(clauses) => {
+                let (excluded, included): (Vec<_>, Vec<_>) = clauses
+                    .iter()
+                    .partition(|clause| matches!(clause, Query::Not(_)));
+
+                // Negated clauses are subtracted directly instead of being
+                // complemented against every document first.
+                let mut result = match included.split_first() {
+                    Some((first, rest)) => rest
+                        .iter()
+                        .fold(first.evaluate(postings, all_documents), |result, clause| {
+                            result & clause.evaluate(postings, all_documents)
+                        }),
+                    None => all_documents.clone(),
+                };
+
+                for clause in excluded {
+                    if let Query::Not(clause) = clause {
+                        result -= clause.evaluate(postings, all_documents);
+                    }
+                }
+
+                result
+            }
+        }
+    }
+}
+
+/// Documents in which the terms, given by their postings and offsets from the first
+/// term, appear at exactly those offsets.
+fn phrase_matches(postings: &[(&LivePostings, u32)]) -> DocumentSet {
+    let Some(((first, _), rest)) = postings.split_first() else {
+        return DocumentSet::new();
+    };
+
+    // Positions are only compared in the documents containing every term
+    let candidates = rest
+        .iter()
+        .fold(first.documents().clone(), |candidates, (postings, _)| {
+            candidates & postings.documents()
+        });
+
+    let ids = candidates.into_iter().filter(|&id| {
+        let Some(rest) = rest
+            .iter()
+            .map(|(postings, offset)| Some((postings.get(id)?, *offset)))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return false;
+        };
+
+        first.get(id).unwrap_or_default().iter().any(|&start| {
+            rest.iter()
+                .all(|(positions, offset)| positions.binary_search(&(start + offset)).is_ok())
+        })
+    });
+
+    sorted_set(ids)
+}
+
+/// Documents in which the two terms appear at most `distance` positions apart.
+fn near_matches(left: &LivePostings, right: &LivePostings, distance: u32) -> DocumentSet {
+    let candidates = left.documents() & right.documents();
+
+    let ids = candidates.into_iter().filter(|&id| {
+        let (Some(left), Some(right)) = (left.get(id), right.get(id)) else {
+            return false;
+        };
+
+        // Both position lists are sorted, so walking them together finds the
+        // closest pair without comparing every combination.
+        let (mut i, mut j) = (0, 0);
+        while i < left.len() && j < right.len() {
+            if left[i].abs_diff(right[j]) <= distance {
+                return true;
+            }
+
+            if left[i] < right[j] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        false
+    });
+
+    sorted_set(ids)
+}
+
+/// Builds a set from IDs in ascending order, which is faster than inserting them
+/// one at a time.
+fn sorted_set(ids: impl IntoIterator<Item = u64>) -> DocumentSet {
+    DocumentSet::from_sorted_iter(ids).expect("IDs are sorted")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inverted_index::analysis::AnalyzerKind;
+    use crate::inverted_index::Postings;
+    use std::collections::HashMap;
+
+    fn term(term: &str) -> Query {
+        Query::Term(term.to_string())
+    }
+
+    fn parse(query: &str, default_operator: Operator) -> QueryResult<Query> {
+        Query::parse(query, default_operator, &Analyzer::default())
+    }
+
+    #[test]
+    fn test_single_term() {
+        assert_eq!(parse("rust", Operator::Or), Ok(term("rust")));
+    }
+
+    #[test]
+    fn test_default_operator_or() {
+        assert_eq!(
+            parse("rust async", Operator::Or),
+            Ok(Query::Or(vec![term("rust"), term("async")]))
+        );
+    }
+
+    #[test]
+    fn test_default_operator_and() {
+        assert_eq!(
+            parse("rust async", Operator::And),
+            Ok(Query::And(vec![term("rust"), term("async")]))
+        );
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("rust OR go AND async", Operator::Or),
+            Ok(Query::Or(vec![
+                term("rust"),
+                Query::And(vec![term("go"), term("async")])
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parentheses_and_not() {
+        assert_eq!(
+            parse("(rust OR go) AND NOT java", Operator::Or),
+            Ok(Query::And(vec![
+                Query::Or(vec![term("rust"), term("go")]),
+                Query::Not(Box::new(term("java")))
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_lowercase_operators_are_terms() {
+        assert_eq!(
+            parse("rust and go", Operator::And),
+            Ok(Query::And(vec![term("rust"), term("and"), term("go")]))
+        );
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(parse("   ", Operator::Or), Err(QueryError::Empty));
+        assert_eq!(
+            parse("rust AND", Operator::Or),
+            Err(QueryError::UnexpectedEnd("a search term"))
+        );
+        assert_eq!(
+            parse("(rust OR go", Operator::Or),
+            Err(QueryError::UnclosedParenthesis(0))
+        );
+        assert_eq!(
+            parse("rust)", Operator::Or),
+            Err(QueryError::UnexpectedToken(")".to_string(), 4))
+        );
+        assert_eq!(
+            parse("OR rust", Operator::Or),
+            Err(QueryError::UnexpectedToken("OR".to_string(), 0))
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // rust: 1 2 3, async: 2 3 4, java: 3
+        let index: HashMap<&str, Postings> = HashMap::from([
+            ("rust", [(1, vec![0]), (2, vec![0]), (3, vec![0])].into()),
+            ("async", [(2, vec![1]), (3, vec![1]), (4, vec![0])].into()),
+            ("java", [(3, vec![2])].into()),
+        ]);
+        let live: HashMap<&str, LivePostings> = index
+            .iter()
+            .map(|(term, postings)| (*term, LivePostings::from(postings)))
+            .collect();
+        let postings = |term: &str| live.get(term);
+        let all_documents = (0..6).collect();
+
+        let evaluate = |query: &str| {
+            parse(query, Operator::Or)
+                .unwrap()
+                .evaluate(&postings, &all_documents)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(evaluate("rust async"), vec![1, 2, 3, 4]);
+        assert_eq!(evaluate("rust AND async"), vec![2, 3]);
+        assert_eq!(evaluate("rust AND async AND NOT java"), vec![2]);
+        assert_eq!(evaluate("NOT rust"), vec![0, 4, 5]);
+        assert_eq!(evaluate("(rust OR java) AND NOT async"), vec![1]);
+    }
+
+    #[test]
+    fn test_scoring_terms() {
+        let query = parse("rust \"thread pool\" AND NOT java", Operator::Or).unwrap();
+
+        assert_eq!(query.scoring_terms(), vec!["rust", "thread", "pool"]);
+    }
+
+    #[test]
+    fn test_words_without_terms_are_skipped() {
+        let analyzer = Analyzer::new(AnalyzerKind::English);
+        let parse = |query| Query::parse(query, Operator::And, &analyzer);
+
+        assert_eq!(parse("the AND threads"), Ok(term("thread")));
+        assert_eq!(parse("threads AND NOT the"), Ok(term("thread")));
+        assert_eq!(
+            parse("\"threads of the pool\""),
+            Ok(Query::Phrase(vec![
+                ("thread".to_string(), 0),
+                ("pool".to_string(), 3)
+            ]))
+        );
+        assert_eq!(parse("the OR !!"), Err(QueryError::NoSearchableTerms));
+    }
+
+    #[test]
+    fn test_phrase_and_near() {
+        assert_eq!(
+            parse("\"thread pool\" OR worker", Operator::Or),
+            Ok(Query::Or(vec![
+                Query::Phrase(vec![("thread".to_string(), 0), ("pool".to_string(), 1)]),
+                term("worker")
+            ]))
+        );
+        assert_eq!(
+            parse("rust NEAR/3 async", Operator::Or),
+            Ok(Query::Near("rust".to_string(), "async".to_string(), 3))
+        );
+        assert_eq!(
+            parse("\"thread pool", Operator::Or),
+            Err(QueryError::UnclosedQuote(0))
+        );
+        assert_eq!(
+            parse("(rust OR go) NEAR/2 async", Operator::Or),
+            Err(QueryError::InvalidNearOperand(2, 13))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_positions() {
+        // 1: "thread pool", 2: "pool thread", 3: "thread in a pool"
+        let index: HashMap<&str, Postings> = HashMap::from([
+            ("thread", [(1, vec![0]), (2, vec![1]), (3, vec![0])].into()),
+            ("pool", [(1, vec![1]), (2, vec![0]), (3, vec![3])].into()),
+        ]);
+        let live: HashMap<&str, LivePostings> = index
+            .iter()
+            .map(|(term, postings)| (*term, LivePostings::from(postings)))
+            .collect();
+        let postings = |term: &str| live.get(term);
+        let all_documents = (1..4).collect();
+
+        let evaluate = |query: &str| {
+            parse(query, Operator::Or)
+                .unwrap()
+                .evaluate(&postings, &all_documents)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(evaluate("\"thread pool\""), vec![1]);
+        assert_eq!(evaluate("thread NEAR/1 pool"), vec![1, 2]);
+        assert_eq!(evaluate("thread NEAR/3 pool"), vec![1, 2, 3]);
+        assert_eq!(evaluate("\"thread missing\""), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let pattern = |query: &str| match parse(query, Operator::Or) {
+            Ok(Query::Pattern(pattern)) => pattern,
+            query => panic!("{query:?} is not a pattern"),
+        };
+
+        assert_eq!(pattern("Paral*").prefix(), "paral");
+        assert!(pattern("paral*").matches("parallel"));
+        assert!(!pattern("paral*").matches("param"));
+        assert!(pattern("c?l*r").matches("colour"));
+        assert!(!pattern("c?l*r").matches("colours"));
+        assert!(pattern("/colou?r/").matches("color"));
+        assert!(!pattern("/colou?r/").matches("colors"));
+        assert!(pattern("/a\\/b/").matches("a/b"));
+
+        assert_eq!(
+            parse("?!", Operator::Or),
+            Err(QueryError::NoSearchableTerms)
+        );
+        assert_eq!(
+            parse("rust /colou?r", Operator::Or),
+            Err(QueryError::UnclosedRegex(5))
+        );
+        assert!(matches!(
+            parse("/colo(u/", Operator::Or),
+            Err(QueryError::InvalidRegex(regex, _)) if regex == "colo(u"
+        ));
+    }
+
+    #[test]
+    fn test_fuzzy_terms() {
+        let analyzer = Analyzer::new(AnalyzerKind::English);
+        let parse = |query| Query::parse(query, Operator::Or, &analyzer);
+        let pattern = |query| match parse(query) {
+            Ok(Query::Pattern(pattern)) => pattern,
+            query => panic!("{query:?} is not a pattern"),
+        };
+
+        assert!(pattern("Colour~1").matches("color"));
+        assert!(!pattern("colour~1").matches("collar"));
+        assert!(pattern("colour~").matches("collar"));
+        assert!(pattern("Runing~1").matches("run"));
+
+        assert_eq!(parse("rust~0"), Ok(term("rust")));
+        assert_eq!(parse("the~1"), Err(QueryError::NoSearchableTerms));
+        assert_eq!(parse("rust~3"), Err(QueryError::InvalidEditDistance(3, 0)));
+        assert_eq!(
+            parse("rust~1 NEAR/2 async"),
+            Err(QueryError::InvalidNearOperand(2, 7))
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let terms = ["parallel", "paralysis", "rust"];
+        let lookup = |pattern: &Pattern, limit: usize| -> Vec<String> {
+            terms
+                .iter()
+                .filter(|term| pattern.matches(term))
+                .take(limit)
+                .map(|term| term.to_string())
+                .collect()
+        };
+        let expand = |query: &str| parse(query, Operator::And).unwrap().expand(&lookup);
+
+        assert_eq!(
+            expand("paral* AND NOT rus?"),
+            Ok(Query::And(vec![
+                Query::Or(vec![term("parallel"), term("paralysis")]),
+                Query::Not(Box::new(term("rust")))
+            ]))
+        );
+        assert_eq!(expand("zig*"), Ok(Query::Or(Vec::new())));
+
+        let many = |_: &Pattern, limit: usize| vec!["term".to_string(); limit];
+        assert_eq!(
+            parse("/.+/", Operator::Or).unwrap().expand(&many),
+            Err(QueryError::TooManyExpansions(
+                "/.+/".to_string(),
+                MAX_EXPANSIONS
+            ))
+        );
+    }
+}
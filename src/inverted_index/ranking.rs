@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+/// Term frequency saturation: how quickly repeated occurrences stop adding to the score.
+const K1: f64 = 1.2;
+/// Document length normalization: 0 ignores the length, 1 fully normalizes by it.
+const B: f64 = 0.75;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub id: u64,
+    pub score: f64,
+    /// Excerpt of the document around the matched terms, if snippets were requested
+    pub snippet: Option<String>,
+}
+
+/// Okapi BM25 scoring over the collection statistics captured at search time.
+pub struct Bm25 {
+    document_count: f64,
+    average_length: f64,
+}
+
+impl Bm25 {
+    pub fn new(document_count: usize, total_length: u64) -> Self {
+        let average_length = if document_count == 0 {
+            0.0
+        } else {
+            total_length as f64 / document_count as f64
+        };
+
+        Bm25 {
+            document_count: document_count as f64,
+            average_length,
+        }
+    }
+
+    /// Contribution of a single term occurring `term_frequency` times in a document of
+    /// `document_length` terms, where `document_frequency` documents contain the term.
+    pub fn score(
+        &self,
+        term_frequency: usize,
+        document_frequency: usize,
+        document_length: u32,
+    ) -> f64 {
+        let term_frequency = term_frequency as f64;
+        let document_frequency = document_frequency as f64;
+
+        let idf = (1.0
+            + (self.document_count - document_frequency + 0.5) / (document_frequency + 0.5))
+            .ln();
+
+        let length_ratio = if self.average_length > 0.0 {
+            document_length as f64 / self.average_length
+        } else {
+            1.0
+        };
+
+        idf * term_frequency * (K1 + 1.0) / (term_frequency + K1 * (1.0 - B + B * length_ratio))
+    }
+}
+
+/// All hits of a search, of which only one page is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Number of matching documents, including those outside of the page
+    pub total: usize,
+    /// Terms of the query without postings -> Similar terms of the index, best first
+    pub suggestions: BTreeMap<String, Vec<String>>,
+}
+
+/// Orders hits by descending score, breaking ties by ID, skips the first `offset` and
+/// keeps at most `limit` of the rest.
+pub fn rank(mut hits: Vec<SearchHit>, offset: usize, limit: Option<usize>) -> SearchResults {
+    let total = hits.len();
+    let order = |a: &SearchHit, b: &SearchHit| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id));
+
+    // Only the hits up to the end of the page need to be sorted
+    let end = limit.map_or(total, |limit| offset.saturating_add(limit).min(total));
+    if end < total {
+        hits.select_nth_unstable_by(end, order);
+        hits.truncate(end);
+    }
+
+    hits.sort_by(order);
+    hits.drain(..offset.min(hits.len()));
+
+    SearchResults {
+        hits,
+        total,
+        suggestions: BTreeMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rare_terms_score_higher() {
+        let bm25 = Bm25::new(100, 1000);
+
+        assert!(bm25.score(1, 1, 10) > bm25.score(1, 50, 10));
+    }
+
+    #[test]
+    fn test_frequent_and_short_score_higher() {
+        let bm25 = Bm25::new(100, 1000);
+
+        assert!(bm25.score(3, 10, 10) > bm25.score(1, 10, 10));
+        assert!(bm25.score(1, 10, 5) > bm25.score(1, 10, 50));
+    }
+
+    #[test]
+    fn test_rank() {
+        let hits = vec![
+            SearchHit {
+                id: 1,
+                score: 0.5,
+                snippet: None,
+            },
+            SearchHit {
+                id: 2,
+                score: 2.0,
+                snippet: None,
+            },
+            SearchHit {
+                id: 0,
+                score: 0.5,
+                snippet: None,
+            },
+        ];
+
+        let ids = |offset, limit| -> Vec<u64> {
+            let results = rank(hits.clone(), offset, limit);
+            assert_eq!(results.total, 3);
+            results.hits.iter().map(|hit| hit.id).collect()
+        };
+
+        assert_eq!(ids(0, Some(2)), vec![2, 0]);
+        assert_eq!(ids(1, Some(1)), vec![0]);
+        assert_eq!(ids(1, None), vec![0, 1]);
+        assert_eq!(ids(2, Some(5)), vec![1]);
+        assert_eq!(ids(3, Some(2)), Vec::<u64>::new());
+    }
+}
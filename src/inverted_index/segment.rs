@@ -0,0 +1,258 @@
+use super::{DocumentSet, Postings};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+/// Number of segments of a similar size that are merged into one.
+pub const MERGE_FACTOR: usize = 10;
+
+/// An immutable part of the index. Documents are indexed into segments of their
+/// own, which are merged into bigger ones in the background.
+///
+/// A segment may still contain documents that have been deleted since it was
+/// built. Every document is stored with the generation it was indexed in, and only
+/// counts while the document table holds the same generation for its ID. Once it
+/// does not any more, its ID is marked as deleted, so that searches can skip it
+/// with a single bitmap difference per term.
+#[derive(Debug, Default)]
+pub struct Segment {
+    // Word -> Postings
+    postings: HashMap<String, Postings>,
+    // ID -> Generation
+    documents: HashMap<u64, u64>,
+    // IDs of the stored documents that were deleted or replaced since
+    deleted: RwLock<DocumentSet>,
+}
+
+impl Segment {
+    /// A segment of `postings` that only counts for `documents`. Postings of other
+    /// documents, which files written by older versions may hold, are deleted.
+    pub fn new(postings: HashMap<String, Postings>, documents: HashMap<u64, u64>) -> Self {
+        let deleted = postings
+            .values()
+            .flat_map(|postings| postings.documents().iter())
+            .filter(|id| !documents.contains_key(id))
+            .collect();
+
+        Segment {
+            postings,
+            documents,
+            deleted: RwLock::new(deleted),
+        }
+    }
+
+    /// A segment holding the words of a single document with their positions.
+    pub fn with_document(id: u64, generation: u64, words: HashMap<String, Vec<u32>>) -> Self {
+        let postings = words
+            .into_iter()
+            .map(|(word, positions)| {
+                let mut postings = Postings::new();
+                postings.push(id, &positions);
+                (word, postings)
+            })
+            .collect();
+
+        Segment::new(postings, HashMap::from([(id, generation)]))
+    }
+
+    /// Builds a segment from the live documents of all `segments`, where
+    /// `is_live(id, generation)` tells whether a stored document still counts.
+    pub fn merge(segments: &[Arc<Segment>], is_live: impl Fn(u64, u64) -> bool) -> Self {
+        let mut merged = Segment::default();
+
+        for segment in segments {
+            for (&id, &generation) in &segment.documents {
+                if is_live(id, generation) {
+                    merged.documents.insert(id, generation);
+                }
+            }
+        }
+
+        // Word -> Live documents of all segments with their positions. Postings of
+        // documents the segment does not store or has marked as deleted are dropped.
+        let mut gathered: HashMap<&str, Vec<(u64, &[u32])>> = HashMap::new();
+        for segment in segments {
+            let deleted = segment.deleted();
+            for (word, postings) in &segment.postings {
+                for (id, positions) in postings.iter() {
+                    let generation = segment.documents.get(&id);
+                    if generation.is_some()
+                        && generation == merged.documents.get(&id)
+                        && !deleted.contains(id)
+                    {
+                        gathered.entry(word).or_default().push((id, positions));
+                    }
+                }
+            }
+        }
+
+        for (word, mut documents) in gathered {
+            documents.sort_unstable_by_key(|&(id, _)| id);
+
+            let mut postings = Postings::new();
+            for (id, positions) in documents {
+                postings.push(id, positions);
+            }
+            merged.postings.insert(word.to_string(), postings);
+        }
+
+        merged
+    }
+
+    pub fn postings(&self, word: &str) -> Option<&Postings> {
+        self.postings.get(word)
+    }
+
+    pub fn all_postings(&self) -> &HashMap<String, Postings> {
+        &self.postings
+    }
+
+    /// Marks the version of a document stored with `generation` as deleted, if the
+    /// segment contains it.
+    pub fn delete(&self, id: u64, generation: u64) {
+        if self.documents.get(&id) == Some(&generation) {
+            self.deleted.write().unwrap().insert(id);
+        }
+    }
+
+    /// IDs of the stored documents that no longer count.
+    pub fn deleted(&self) -> RwLockReadGuard<'_, DocumentSet> {
+        self.deleted.read().unwrap()
+    }
+
+    /// Generation of the stored version of a document, if the segment contains it.
+    pub fn generation(&self, id: u64) -> Option<u64> {
+        self.documents.get(&id).cloned()
+    }
+
+    /// IDs of the stored documents with their generations, including deleted ones.
+    pub fn documents(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.documents
+            .iter()
+            .map(|(&id, &generation)| (id, generation))
+    }
+
+    /// Number of stored documents, including deleted ones.
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+}
+
+/// Tiered merge policy: segments are grouped by the order of magnitude of their
+/// size, and once a tier holds [`MERGE_FACTOR`] segments they are merged into one
+/// of the next tier. Every document is therefore merged only a logarithmic number
+/// of times.
+pub fn select_merge(segments: &[Arc<Segment>]) -> Option<Vec<Arc<Segment>>> {
+    let mut tiers: HashMap<u32, Vec<&Arc<Segment>>> = HashMap::new();
+
+    for segment in segments {
+        let tier = segment.len().max(1).ilog(MERGE_FACTOR);
+        tiers.entry(tier).or_default().push(segment);
+    }
+
+    let (_, mut candidates) = tiers
+        .into_iter()
+        .filter(|(_, segments)| segments.len() >= MERGE_FACTOR)
+        .min_by_key(|(tier, _)| *tier)?;
+
+    candidates.sort_by_key(|segment| segment.len());
+    candidates.truncate(MERGE_FACTOR);
+
+    Some(candidates.into_iter().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(id: u64, generation: u64, words: &[&str]) -> Arc<Segment> {
+        let words = words
+            .iter()
+            .enumerate()
+            .map(|(position, word)| (word.to_string(), vec![position as u32]))
+            .collect();
+
+        Arc::new(Segment::with_document(id, generation, words))
+    }
+
+    #[test]
+    fn test_merge_keeps_live_documents() {
+        let segments = [
+            segment(0, 1, &["rust", "async"]),
+            segment(1, 2, &["rust"]),
+            // An older version of document 1
+            segment(1, 0, &["python"]),
+        ];
+
+        let merged = Segment::merge(&segments, |id, generation| id == 0 || generation == 2);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged.generation(1), Some(2));
+        assert_eq!(
+            merged.postings("rust"),
+            Some(&Postings::from([(0, vec![0]), (1, vec![0])]))
+        );
+        assert!(merged.postings("python").is_none());
+
+        let merged = Segment::merge(&segments, |id, _| id == 1);
+        assert!(merged.postings("async").is_none());
+    }
+
+    #[test]
+    fn test_deleted_documents() {
+        let segment = Segment::new(
+            HashMap::from([(
+                "rust".to_string(),
+                Postings::from([(0, vec![0]), (1, vec![0])]),
+            )]),
+            HashMap::from([(0, 3)]),
+        );
+        assert_eq!(*segment.deleted(), DocumentSet::from_iter([1]));
+
+        // Only the stored version of a document is deleted
+        segment.delete(0, 2);
+        assert_eq!(segment.deleted().len(), 1);
+        segment.delete(0, 3);
+        assert_eq!(*segment.deleted(), DocumentSet::from_iter([0, 1]));
+    }
+
+    #[test]
+    fn test_merge_drops_orphan_postings() {
+        // Written by an older version, with postings of a document it does not store
+        let legacy = Arc::new(Segment::new(
+            HashMap::from([(
+                "ghost".to_string(),
+                Postings::from([(0, vec![0]), (7, vec![0])]),
+            )]),
+            HashMap::from([(0, 0)]),
+        ));
+
+        let merged = Segment::merge(&[legacy], |_, _| true);
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            merged.postings("ghost"),
+            Some(&Postings::from([(0, vec![0])]))
+        );
+        assert!(merged.deleted().is_empty());
+    }
+
+    #[test]
+    fn test_select_merge() {
+        let mut segments: Vec<_> = (0..MERGE_FACTOR as u64 - 1)
+            .map(|id| segment(id, 0, &["rust"]))
+            .collect();
+        assert!(select_merge(&segments).is_none());
+
+        segments.push(segment(100, 0, &["rust"]));
+        let selected = select_merge(&segments).unwrap();
+        assert_eq!(selected.len(), MERGE_FACTOR);
+
+        let merged = Arc::new(Segment::merge(&selected, |_, _| true));
+        assert_eq!(merged.len(), MERGE_FACTOR);
+        assert!(select_merge(&[merged]).is_none());
+    }
+}
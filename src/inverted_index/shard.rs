@@ -0,0 +1,89 @@
+use super::segment::Segment;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+/// Number of partitions of the term dictionary.
+pub const SHARD_COUNT: usize = 16;
+
+/// The segments of all terms that hash to the same shard. Every shard has its own
+/// locks, so indexing and merging in one shard never waits for another.
+#[derive(Debug, Default)]
+pub struct Shard {
+    segments: RwLock<Vec<Arc<Segment>>>,
+    // Held while segments are merged, so that only one merge per shard runs at a time
+    merge_lock: Mutex<()>,
+}
+
+impl Shard {
+    pub fn new(segment: Segment) -> Self {
+        let segments = if segment.is_empty() {
+            Vec::new()
+        } else {
+            vec![Arc::new(segment)]
+        };
+
+        Shard {
+            segments: RwLock::new(segments),
+            merge_lock: Mutex::new(()),
+        }
+    }
+
+    /// A copy of the segment list, which can be searched without holding any lock.
+    pub fn segments(&self) -> Vec<Arc<Segment>> {
+        self.segments.read().unwrap().clone()
+    }
+
+    pub fn push(&self, segment: Arc<Segment>) {
+        self.segments.write().unwrap().push(segment);
+    }
+
+    /// Replaces the segments in `merged` with `segment`, keeping segments that were
+    /// added in the meantime. Documents deleted while the segments were merged are
+    /// marked as deleted in `segment` as well.
+    pub fn replace(&self, merged: &[Arc<Segment>], segment: Arc<Segment>) {
+        let mut segments = self.segments.write().unwrap();
+        segments.retain(|s| !merged.iter().any(|merged| Arc::ptr_eq(merged, s)));
+
+        for old in merged {
+            for id in old.deleted().iter() {
+                if let Some(generation) = old.generation(id) {
+                    segment.delete(id, generation);
+                }
+            }
+        }
+
+        if !segment.is_empty() {
+            segments.push(segment);
+        }
+    }
+
+    pub fn remove(&self, removed: &[Arc<Segment>]) {
+        let mut segments = self.segments.write().unwrap();
+        segments.retain(|s| !removed.iter().any(|removed| Arc::ptr_eq(removed, s)));
+    }
+
+    /// Marks the version of a document stored with `generation` as deleted in every
+    /// segment. Segments are not replaced in the meantime, so no mark gets lost.
+    pub fn delete(&self, id: u64, generation: u64) {
+        for segment in self.segments.read().unwrap().iter() {
+            segment.delete(id, generation);
+        }
+    }
+
+    pub fn lock_merges(&self) -> MutexGuard<'_, ()> {
+        self.merge_lock.lock().unwrap()
+    }
+
+    /// Returns `None` if a merge is already running in this shard.
+    pub fn try_lock_merges(&self) -> Option<MutexGuard<'_, ()>> {
+        self.merge_lock.try_lock().ok()
+    }
+}
+
+/// Index of the shard holding `term`.
+pub fn shard_of(term: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    term.hash(&mut hasher);
+
+    (hasher.finish() % SHARD_COUNT as u64) as usize
+}
@@ -0,0 +1,117 @@
+use super::analysis::Analyzer;
+use super::tokenize;
+use std::collections::HashSet;
+
+/// Markers placed around every matched term of a snippet.
+pub const HIGHLIGHT_START: &str = "<mark>";
+pub const HIGHLIGHT_END: &str = "</mark>";
+
+/// Number of tokens, words and punctuation alike, in a snippet.
+const SNIPPET_TOKENS: usize = 30;
+/// Number of tokens shown before the first match of a snippet.
+const CONTEXT_TOKENS: usize = 5;
+
+/// A short excerpt of `text` around the densest cluster of `terms`, with every term
+/// highlighted. Texts without any of the terms are excerpted from the start.
+///
+/// `terms` are analyzed terms, as found in the index. Whitespace between tokens is
+/// collapsed into a single space.
+pub fn snippet(text: &str, analyzer: &Analyzer, terms: &HashSet<&str>) -> String {
+    let tokens: Vec<(usize, &str)> = tokenize::tokenize_with_offsets(text).collect();
+
+    // Analyzed tokens keep their index into `tokens`
+    let matches: Vec<usize> = analyzer
+        .analyze(text)
+        .into_iter()
+        .filter(|token| terms.contains(token.text.as_str()))
+        .map(|token| token.index)
+        .collect();
+
+    // The first match of the window with the most matches
+    let mut densest = (0, 0);
+    let mut window_end = 0;
+    for (i, &first) in matches.iter().enumerate() {
+        while window_end < matches.len()
+            && matches[window_end] < first + SNIPPET_TOKENS - CONTEXT_TOKENS
+        {
+            window_end += 1;
+        }
+
+        if window_end - i > densest.0 {
+            densest = (window_end - i, first);
+        }
+    }
+
+    let start = densest.1.saturating_sub(CONTEXT_TOKENS);
+    let end = (start + SNIPPET_TOKENS).min(tokens.len());
+    let matches: HashSet<usize> = matches.into_iter().collect();
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push_str("… ");
+    }
+
+    let mut previous_end = None;
+    for (position, &(offset, token)) in tokens.iter().enumerate().take(end).skip(start) {
+        if previous_end.is_some_and(|previous_end| previous_end < offset) {
+            snippet.push(' ');
+        }
+
+        if matches.contains(&position) {
+            snippet.push_str(HIGHLIGHT_START);
+            snippet.push_str(token);
+            snippet.push_str(HIGHLIGHT_END);
+        } else {
+            snippet.push_str(token);
+        }
+
+        previous_end = Some(offset + token.len());
+    }
+
+    if end < tokens.len() {
+        snippet.push_str(" …");
+    }
+
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::AnalyzerKind;
+    use super::*;
+
+    #[test]
+    fn test_snippet_highlights_terms() {
+        let analyzer = Analyzer::new(AnalyzerKind::English);
+        let terms = HashSet::from(["thread", "pool"]);
+
+        assert_eq!(
+            snippet("A  Thread pool,\nreused.", &analyzer, &terms),
+            "A <mark>Thread</mark> <mark>pool</mark>, reused."
+        );
+    }
+
+    #[test]
+    fn test_snippet_around_densest_matches() {
+        let analyzer = Analyzer::new(AnalyzerKind::Standard);
+        let filler = |count| vec!["filler"; count].join(" ");
+        let text = format!("rust {} rust and rust again {}", filler(100), filler(100));
+
+        let snippet = snippet(&text, &analyzer, &HashSet::from(["rust"]));
+
+        assert!(snippet.starts_with("… filler filler filler filler filler <mark>rust</mark> and"));
+        assert!(snippet.ends_with(" …"));
+        assert_eq!(snippet.matches(HIGHLIGHT_START).count(), 2);
+    }
+
+    #[test]
+    fn test_snippet_without_matches() {
+        let analyzer = Analyzer::new(AnalyzerKind::Standard);
+
+        assert_eq!(
+            snippet("nothing to see", &analyzer, &HashSet::from(["rust"])),
+            "nothing to see"
+        );
+        assert_eq!(snippet("", &analyzer, &HashSet::new()), "");
+    }
+}
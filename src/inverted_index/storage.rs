@@ -0,0 +1,478 @@
+use super::mapped::MappedIndex;
+use super::{binary, AnalyzerKind, Document, Metadata, PendingDocument, Postings};
+use memmap2::Mmap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    #[error("Failed to read state file: {0}")]
+    FailedToRead(std::io::Error),
+
+    #[error("State file is not valid JSON: {0}")]
+    InvalidJson(serde_json::Error),
+
+    #[error("State file uses unsupported format version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("State file checksum does not match its content")]
+    ChecksumMismatch,
+
+    #[error("State file has an invalid {0}")]
+    InvalidField(&'static str),
+
+    #[error("No backup of the state file to restore")]
+    NoBackup,
+
+    #[error("Failed to read write-ahead log: {0}")]
+    FailedToReadLog(std::io::Error),
+}
+
+pub type LoadResult<T> = std::result::Result<T, LoadError>;
+
+/// What to do when the state file exists but cannot be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Recovery {
+    /// Refuse to start, so that the operator can inspect the file
+    #[default]
+    Fail,
+    /// Load the state saved before the last successful save
+    Backup,
+    /// Start with an empty index
+    Empty,
+    /// Start with an empty index, which the server fills again from the uploaded files
+    /// with [`InvertedIndex::reconcile_uploads`]
+    ///
+    /// [`InvertedIndex::reconcile_uploads`]: super::InvertedIndex::reconcile_uploads
+    Rebuild,
+}
+
+impl Recovery {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Recovery::Fail => "fail",
+            Recovery::Backup => "backup",
+            Recovery::Empty => "empty",
+            Recovery::Rebuild => "rebuild",
+        }
+    }
+}
+
+impl FromStr for Recovery {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "fail" => Ok(Recovery::Fail),
+            "backup" => Ok(Recovery::Backup),
+            "empty" => Ok(Recovery::Empty),
+            "rebuild" => Ok(Recovery::Rebuild),
+            _ => Err(format!("Unknown recovery mode: {name}")),
+        }
+    }
+}
+
+/// Everything read from a state file.
+#[derive(Default)]
+pub struct State {
+    pub index: HashMap<String, Postings>,
+    // Postings left in the mapped state file instead of `index`
+    pub base: Option<MappedIndex>,
+    pub documents: HashMap<u64, Document>,
+    pub pending: HashMap<u64, PendingDocument>,
+    pub last_document_id: u64,
+    pub analyzer: AnalyzerKind,
+}
+
+/// A view of the index while it is locked for saving.
+pub struct Snapshot<'a> {
+    // Postings of every shard, which hold disjoint sets of terms
+    pub index: Vec<&'a HashMap<String, Postings>>,
+    // Postings of the mapped state file, which count for documents of generation 0
+    pub base: Option<&'a MappedIndex>,
+    pub documents: &'a HashMap<u64, Document>,
+    pub pending: &'a HashMap<u64, PendingDocument>,
+    pub last_document_id: u64,
+    pub analyzer: AnalyzerKind,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Every term in sorted order, with the postings of the documents in the
+    /// snapshot. Terms without any are skipped.
+    pub fn postings(&self) -> impl Iterator<Item = (&'a str, Postings)> + '_ {
+        let overlay: BTreeMap<&str, &Postings> = self
+            .index
+            .iter()
+            .flat_map(|postings| postings.iter())
+            .map(|(term, postings)| (term.as_str(), postings))
+            .collect();
+        let mut overlay = overlay.into_iter().peekable();
+        let mut base = self
+            .base
+            .into_iter()
+            .flat_map(MappedIndex::terms)
+            .peekable();
+
+        std::iter::from_fn(move || loop {
+            let term = match (base.peek(), overlay.peek()) {
+                (Some(&base_term), Some(&(overlay_term, _))) => base_term.min(overlay_term),
+                (Some(&term), None) | (None, Some(&(term, _))) => term,
+                (None, None) => return None,
+            };
+
+            let mut documents: Vec<(u64, Vec<u32>)> = Vec::new();
+
+            if base.next_if_eq(&term).is_some() {
+                let base_postings = self.base.and_then(|base| base.postings(term));
+                for (id, positions) in base_postings.iter().flat_map(Postings::iter) {
+                    if self
+                        .documents
+                        .get(&id)
+                        .is_some_and(|document| document.generation == 0)
+                    {
+                        documents.push((id, positions.to_vec()));
+                    }
+                }
+            }
+
+            if let Some((_, overlay_postings)) = overlay.next_if(|&(t, _)| t == term) {
+                documents.extend(
+                    overlay_postings
+                        .iter()
+                        .filter(|(id, _)| self.documents.contains_key(id))
+                        .map(|(id, positions)| (id, positions.to_vec())),
+                );
+            }
+
+            let postings: Postings = documents.into_iter().collect();
+            if !postings.is_empty() {
+                return Some((term, postings));
+            }
+        })
+    }
+}
+
+pub fn backup_file(state_file: &str) -> String {
+    format!("{state_file}.bak")
+}
+
+/// Where a state file that failed to load is moved, so that it is neither loaded
+/// nor overwritten again.
+pub fn corrupt_file(state_file: &str) -> String {
+    format!("{state_file}.corrupt")
+}
+
+/// Reads the state file at `path`, or returns `None` if there is none.
+///
+/// Both the binary format and the JSON format of older versions are accepted. With
+/// `mmap`, a binary file is mapped into memory and its postings are decoded only
+/// when they are looked up.
+pub fn read_state(path: &str, mmap: bool) -> LoadResult<Option<State>> {
+    if mmap {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(LoadError::FailedToRead(e)),
+        };
+
+        // SAFETY: state files are only ever replaced by renaming a new file over
+        // them, never modified in place, so the mapping does not change under us.
+        let map = unsafe { Mmap::map(&file) }.map_err(LoadError::FailedToRead)?;
+
+        if map.starts_with(binary::MAGIC) {
+            return MappedIndex::load(map).map(Some);
+        }
+
+        return read_json(&map).map(Some);
+    }
+
+    let raw_data = match std::fs::read(path) {
+        Ok(raw_data) => raw_data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(LoadError::FailedToRead(e)),
+    };
+
+    if raw_data.starts_with(binary::MAGIC) {
+        return binary::decode(&raw_data).map(Some);
+    }
+
+    read_json(&raw_data).map(Some)
+}
+
+fn read_json(raw_data: &[u8]) -> LoadResult<State> {
+    let raw_data: serde_json::Value =
+        serde_json::from_slice(raw_data).map_err(LoadError::InvalidJson)?;
+
+    let index = raw_data["index"]
+        .as_object()
+        .ok_or(LoadError::InvalidField("index"))?
+        .iter()
+        .map(|(word, postings)| Ok((word.to_string(), parse_postings(postings)?)))
+        .collect::<LoadResult<_>>()?;
+
+    let documents = raw_data["documents"]
+        .as_object()
+        .ok_or(LoadError::InvalidField("documents"))?
+        .iter()
+        .map(|(id, document)| {
+            let id = parse_id(id)?;
+            Ok((id, parse_document(id, document, &index)?))
+        })
+        .collect::<LoadResult<_>>()?;
+
+    let last_document_id = raw_data["last_document_id"]
+        .as_u64()
+        .ok_or(LoadError::InvalidField("last_document_id"))?;
+
+    // Index files written before analyzers existed contain raw tokens
+    let analyzer = match raw_data["analyzer"].as_str() {
+        Some(name) => name
+            .parse()
+            .map_err(|_| LoadError::InvalidField("analyzer"))?,
+        None => AnalyzerKind::Raw,
+    };
+
+    // Index files written before the write-ahead log existed never contain pending
+    // documents
+    let pending = match raw_data["pending"].as_object() {
+        Some(pending) => pending
+            .iter()
+            .map(|(id, document)| Ok((parse_id(id)?, parse_pending_document(document)?)))
+            .collect::<LoadResult<_>>()?,
+        None => HashMap::new(),
+    };
+
+    Ok(State {
+        index,
+        base: None,
+        documents,
+        pending,
+        last_document_id,
+        analyzer,
+    })
+}
+
+/// The index in the JSON format of older versions, for export.
+pub fn to_json(snapshot: &Snapshot) -> serde_json::Value {
+    let index: BTreeMap<_, _> = snapshot
+        .postings()
+        .map(|(term, postings)| {
+            let postings: BTreeMap<u64, &[u32]> = postings.iter().collect();
+            (term, serde_json::json!(postings))
+        })
+        .collect();
+
+    let documents: HashMap<_, _> = snapshot
+        .documents
+        .iter()
+        .map(|(id, document)| {
+            let document = serde_json::json!({
+                "path": document.path,
+                "length": document.length,
+                "metadata": document.metadata.to_json(),
+            });
+            (id, document)
+        })
+        .collect();
+
+    let pending: HashMap<_, _> = snapshot
+        .pending
+        .iter()
+        .map(|(id, document)| {
+            let document = serde_json::json!({
+                "path": document.path,
+                "metadata": document.metadata.to_json(),
+            });
+            (id, document)
+        })
+        .collect();
+
+    serde_json::json!({
+        "index": index,
+        "documents": documents,
+        "pending": pending,
+        "last_document_id": snapshot.last_document_id,
+        "analyzer": snapshot.analyzer.name(),
+    })
+}
+
+fn parse_id(id: &str) -> LoadResult<u64> {
+    id.parse()
+        .map_err(|_| LoadError::InvalidField("document ID"))
+}
+
+fn parse_document(
+    id: u64,
+    document: &serde_json::Value,
+    index: &HashMap<String, Postings>,
+) -> LoadResult<Document> {
+    // Index files written before lengths were tracked map IDs straight to paths,
+    // so the length is recovered from the postings instead.
+    if let Some(path) = document.as_str() {
+        let length = index
+            .values()
+            .filter_map(|postings| postings.get(id))
+            .map(|positions| positions.len().max(1) as u32)
+            .sum();
+
+        return Ok(Document {
+            path: path.to_string(),
+            length,
+            generation: 0,
+            metadata: Metadata::default(),
+        });
+    }
+
+    Ok(Document {
+        path: document["path"]
+            .as_str()
+            .ok_or(LoadError::InvalidField("document path"))?
+            .to_string(),
+        length: document["length"]
+            .as_u64()
+            .ok_or(LoadError::InvalidField("document length"))? as u32,
+        generation: 0,
+        metadata: parse_metadata(document)?,
+    })
+}
+
+fn parse_pending_document(document: &serde_json::Value) -> LoadResult<PendingDocument> {
+    // Index files written before metadata existed map IDs straight to paths
+    if let Some(path) = document.as_str() {
+        return Ok(PendingDocument {
+            path: path.to_string(),
+            metadata: Metadata::default(),
+        });
+    }
+
+    Ok(PendingDocument {
+        path: document["path"]
+            .as_str()
+            .ok_or(LoadError::InvalidField("pending"))?
+            .to_string(),
+        metadata: parse_metadata(document)?,
+    })
+}
+
+fn parse_metadata(document: &serde_json::Value) -> LoadResult<Metadata> {
+    match document.get("metadata") {
+        Some(metadata) => Metadata::from_json(metadata).ok_or(LoadError::InvalidField("metadata")),
+        None => Ok(Metadata::default()),
+    }
+}
+
+fn parse_postings(postings: &serde_json::Value) -> LoadResult<Postings> {
+    // Index files written before positions were tracked store a plain list of
+    // IDs. Those documents still match terms, but never phrases.
+    if let Some(ids) = postings.as_array() {
+        return ids
+            .iter()
+            .map(|id| {
+                let id = id.as_u64().ok_or(LoadError::InvalidField("document ID"))?;
+                Ok((id, Vec::new()))
+            })
+            .collect();
+    }
+
+    postings
+        .as_object()
+        .ok_or(LoadError::InvalidField("postings"))?
+        .iter()
+        .map(|(id, positions)| {
+            let positions = positions
+                .as_array()
+                .ok_or(LoadError::InvalidField("positions"))?
+                .iter()
+                .map(|position| {
+                    let position = position
+                        .as_u64()
+                        .ok_or(LoadError::InvalidField("positions"))?;
+                    Ok(position as u32)
+                })
+                .collect::<LoadResult<_>>()?;
+            Ok((parse_id(id)?, positions))
+        })
+        .collect()
+}
+
+/// Replaces the file at `path` with `data` so that a crash at any point leaves
+/// either the old or the new content in place, never a mix of both.
+///
+/// The previous content is kept in the backup file.
+pub fn write_atomically(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let temp_file = format!("{path}.tmp");
+
+    let mut file = File::create(&temp_file)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    if Path::new(path).exists() {
+        let backup_file = backup_file(path);
+        let _ = std::fs::remove_file(&backup_file);
+
+        // A hard link keeps the old content without copying it
+        std::fs::hard_link(path, &backup_file)
+            .or_else(|_| std::fs::copy(path, &backup_file).map(|_| ()))?;
+    }
+
+    std::fs::rename(&temp_file, path)?;
+
+    // The rename itself is only durable once the directory is synced
+    let directory = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_write_atomically_keeps_backup() {
+        let dir = TestDir::new();
+        let path = dir.join("index.json");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(
+            std::fs::read_to_string(backup_file(&path)).unwrap(),
+            "first"
+        );
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+    }
+
+    #[test]
+    fn test_read_state_errors() {
+        let dir = TestDir::new();
+        let path = dir.join("index.json");
+
+        assert!(read_state(&path, false).unwrap().is_none());
+        assert!(read_state(&path, true).unwrap().is_none());
+
+        std::fs::write(&path, r#"{"index": {"rust": [0]}, "documents": {"0": "a"#).unwrap();
+        assert!(matches!(
+            read_state(&path, false),
+            Err(LoadError::InvalidJson(_))
+        ));
+
+        std::fs::write(&path, r#"{"index": {}, "documents": {"zero": "a.txt"}}"#).unwrap();
+        assert!(matches!(
+            read_state(&path, false),
+            Err(LoadError::InvalidField("document ID"))
+        ));
+
+        std::fs::write(&path, r#"{"index": {}, "documents": {}}"#).unwrap();
+        assert!(matches!(
+            read_state(&path, false),
+            Err(LoadError::InvalidField("last_document_id"))
+        ));
+    }
+}
@@ -0,0 +1,42 @@
+use super::InvertedIndex;
+use std::fs::{self, File};
+use std::io::Write;
+
+/// A directory of its own for the files of a test, removed together with
+/// everything in it when the test ends, whether it passes or not.
+pub struct TestDir {
+    pub path: String,
+}
+
+impl TestDir {
+    pub fn new() -> Self {
+        let path = format!("test_dir_{}", uuid::Uuid::new_v4());
+        fs::create_dir(&path).expect("Failed to create test directory");
+        TestDir { path }
+    }
+
+    /// Path of `name` inside the directory.
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{name}", self.path)
+    }
+
+    /// Creates a file with `content` under a unique name and returns its path.
+    pub fn file(&self, content: &str) -> String {
+        let file_path = self.join(&format!("file_{}.txt", uuid::Uuid::new_v4()));
+        let mut file = File::create(&file_path).expect("Failed to create test file");
+        writeln!(file, "{}", content).expect("Failed to write to test file");
+        file_path
+    }
+
+    /// An index backed by a state file in this directory, so that it does not
+    /// observe documents saved by tests running in parallel.
+    pub fn index(&self) -> InvertedIndex {
+        InvertedIndex::with_state_file(self.join("index.bin"))
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -1,79 +1,62 @@
-
+use super::test_dir::TestDir;
 use super::*;
-use std::fs::{self, File};
-use std::io::Write;
-
-const TEST_STATE_FILE: &str = "index.json";
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 
-// Mock STATE_FILE
-lazy_static::lazy_static! {
-    static ref TEST_STATE_FILE_PATH: String = TEST_STATE_FILE.to_string();
-}
-
-fn setup() {
-    let _ = fs::remove_file(&*TEST_STATE_FILE_PATH); // Ensure no state file exists
-}
-
-fn teardown() {
-    let _ = fs::remove_file(&*TEST_STATE_FILE_PATH); // Clean up
-}
-
-fn create_test_file(content: &str) -> String {
-    let file_path = format!("test_file_{}.txt", uuid::Uuid::new_v4());
-    let mut file = File::create(&file_path).expect("Failed to create test file");
-    writeln!(file, "{}", content).expect("Failed to write to test file");
-    file_path
+/// IDs of all documents matching `query`, in ascending order rather than by rank.
+fn search_ids(index: &InvertedIndex, query: &str, options: &SearchOptions) -> Vec<u64> {
+    let mut ids: Vec<u64> = index
+        .search(query, options)
+        .unwrap()
+        .into_iter()
+        .map(|hit| hit.id)
+        .collect();
+    ids.sort();
+    ids
 }
 
 #[test]
 fn test_new_creates_empty_index() {
-    setup();
-    let index = InvertedIndex::new();
+    let dir = TestDir::new();
+    let index = dir.index();
     assert_eq!(index.get_document_count(), 0);
-    teardown();
 }
 
 #[test]
 fn test_add_document() {
-    setup();
-    let index = InvertedIndex::new();
-    let file_path = create_test_file("rust programming language");
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file_path = dir.file("rust programming language");
 
-    index.add_document(file_path.clone());
+    index.add_document(file_path.clone()).unwrap();
     assert_eq!(index.get_document_count(), 1);
 
-    let search_results = index.search("rust");
+    let search_results = index.search("rust", &SearchOptions::default()).unwrap();
     assert_eq!(search_results.len(), 1);
-
-    fs::remove_file(file_path).unwrap();
-    teardown();
 }
 
 #[test]
 fn test_search() {
-    setup();
-    let index = InvertedIndex::new();
-    let file1 = create_test_file("rust programming language");
-    let file2 = create_test_file("rustaceans love rust");
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file1 = dir.file("rust programming language");
+    let file2 = dir.file("rustaceans love rust");
 
-    index.add_document(file1.clone());
-    index.add_document(file2.clone());
+    index.add_document(file1.clone()).unwrap();
+    index.add_document(file2.clone()).unwrap();
 
-    let search_results = index.search("rust");
+    let search_results = index.search("rust", &SearchOptions::default()).unwrap();
     assert_eq!(search_results.len(), 2);
-
-    fs::remove_file(file1).unwrap();
-    fs::remove_file(file2).unwrap();
-    teardown();
 }
 
 #[test]
 fn test_delete_document() {
-    setup();
-    let index = InvertedIndex::new();
-    let file_path = create_test_file("hello world");
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file_path = dir.file("hello world");
 
-    index.add_document(file_path.clone());
+    index.add_document(file_path.clone()).unwrap();
     let doc_id = index
         .last_document_id
         .load(std::sync::atomic::Ordering::SeqCst)
@@ -82,38 +65,1176 @@ fn test_delete_document() {
     assert!(index.document_exists(doc_id));
     index.delete_document(doc_id).unwrap();
     assert!(!index.document_exists(doc_id));
-
-    teardown();
 }
 
 #[test]
 fn test_save_and_load() {
-    setup();
+    let dir = TestDir::new();
     {
-        let index = InvertedIndex::new();
-        let file_path = create_test_file("save and load test");
-        index.add_document(file_path.clone());
-        fs::remove_file(file_path).unwrap();
+        let index = dir.index();
+        let file_path = dir.file("save and load test");
+        index.add_document(file_path.clone()).unwrap();
     }
 
-    let index = InvertedIndex::new();
+    let index = dir.index();
     assert_eq!(index.get_document_count(), 1);
-    teardown();
 }
 
 #[test]
 fn test_get_document_path() {
-    setup();
-    let index = InvertedIndex::new();
-    let file_path = create_test_file("document path test");
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file_path = dir.file("document path test");
 
-    index.add_document(file_path.clone());
+    index.add_document(file_path.clone()).unwrap();
     let doc_id = index
         .last_document_id
         .load(std::sync::atomic::Ordering::SeqCst)
         - 1;
 
     assert_eq!(index.get_document_path(doc_id), Some(file_path.clone()));
-    fs::remove_file(file_path).unwrap();
-    teardown();
+}
+
+#[test]
+fn test_register_document_reserves_id_before_indexing() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file_path = dir.file("reserved identifier");
+
+    let doc_id = index
+        .register_document(file_path.clone(), Metadata::default())
+        .unwrap();
+
+    assert!(index.document_exists(doc_id));
+    assert_eq!(index.get_document_path(doc_id), Some(file_path.clone()));
+    assert!(index
+        .search("reserved", &SearchOptions::default())
+        .unwrap()
+        .is_empty());
+
+    index.index_document(doc_id);
+
+    assert_eq!(
+        search_ids(&index, "reserved", &SearchOptions::default()),
+        vec![doc_id]
+    );
+}
+
+#[test]
+fn test_boolean_search() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("rust async runtime"),
+        dir.file("rust borrow checker"),
+        dir.file("python async await"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let search = |query: &str, default_operator: Operator| {
+        let options = SearchOptions {
+            default_operator,
+            ..Default::default()
+        };
+        search_ids(&index, query, &options)
+    };
+
+    assert_eq!(search("rust AND async", Operator::Or), vec![ids[0]]);
+    assert_eq!(search("rust async", Operator::And), vec![ids[0]]);
+    assert_eq!(search("rust async", Operator::Or), ids);
+    assert_eq!(search("async AND NOT rust", Operator::Or), vec![ids[2]]);
+    assert_eq!(
+        search("(borrow OR await) AND NOT python", Operator::Or),
+        vec![ids[1]]
+    );
+}
+
+#[test]
+fn test_search_syntax_error() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    assert_eq!(
+        index.search("rust AND (async", &SearchOptions::default()),
+        Err(QueryError::UnclosedParenthesis(9))
+    );
+}
+
+#[test]
+fn test_phrase_search() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("the thread pool runs jobs"),
+        dir.file("a pool for every thread"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    assert_eq!(search("\"thread pool\""), vec![ids[0]]);
+    assert_eq!(search("\"pool thread\""), Vec::<u64>::new());
+    assert_eq!(search("pool NEAR/1 thread"), vec![ids[0]]);
+    assert_eq!(search("pool NEAR/3 thread"), ids);
+}
+
+#[test]
+fn test_phrase_search_across_punctuation() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let id = index.add_document(dir.file("Hello, world!")).unwrap();
+
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    assert_eq!(search("\"hello world\""), vec![id]);
+    assert_eq!(search("\"hello, world\""), vec![id]);
+    assert_eq!(search("hello NEAR/1 world"), vec![id]);
+}
+
+#[test]
+fn test_load_postings_without_positions() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.json");
+    fs::write(
+        &state_file,
+        r#"{"index": {"rust": [0]}, "documents": {"0": "doc.txt"}, "last_document_id": 1}"#,
+    )
+    .unwrap();
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let options = SearchOptions::default();
+
+        assert_eq!(index.search("rust", &options).unwrap().len(), 1);
+        assert!(index.search("\"rust rust\"", &options).unwrap().is_empty());
+    }
+}
+
+#[test]
+fn test_search_ranks_by_relevance() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("rust is a language and a community"),
+        dir.file("rust rust rust"),
+        dir.file("the rust book about async rust"),
+        dir.file("python"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let hits = index.search("rust", &SearchOptions::default()).unwrap();
+    let ranked: Vec<u64> = hits.iter().map(|hit| hit.id).collect();
+
+    assert_eq!(ranked, vec![ids[1], ids[2], ids[0]]);
+    assert!(hits.windows(2).all(|pair| pair[0].score > pair[1].score));
+
+    let options = SearchOptions {
+        limit: Some(1),
+        ..Default::default()
+    };
+    let hits = index.search("rust async", &options).unwrap();
+
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].id, ids[2]);
+
+    let options = SearchOptions {
+        offset: 1,
+        limit: Some(1),
+        ..Default::default()
+    };
+    let results = index.search_page("rust", &options).unwrap();
+
+    assert_eq!(results.total, 3);
+    assert_eq!(results.hits.len(), 1);
+    assert_eq!(results.hits[0].id, ids[2]);
+}
+
+#[test]
+fn test_search_patterns() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("parallel computing"),
+        dir.file("paralysis"),
+        dir.file("colour and color"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    assert_eq!(search("Paral*"), vec![ids[0], ids[1]]);
+    assert_eq!(search("paral* AND comp?ting"), vec![ids[0]]);
+    assert_eq!(search("/colou?r/"), vec![ids[2]]);
+    assert_eq!(search("/paral.*/ AND NOT /.*sis/"), vec![ids[0]]);
+    assert_eq!(search("zig*"), Vec::<u64>::new());
+
+    // Terms of documents indexed after the index was created are found as well
+    let file = dir.file("parallelism");
+    let id = index.add_document(file.clone()).unwrap();
+    assert_eq!(search("parallel*"), vec![ids[0], id]);
+}
+
+#[test]
+fn test_fuzzy_search() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("parallel computing"),
+        dir.file("colour theory"),
+        dir.file("color and colors"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    assert_eq!(search("paralel~1"), vec![ids[0]]);
+    assert_eq!(search("prallel~1 AND computnig~"), vec![ids[0]]);
+    assert_eq!(search("colour~1"), vec![ids[1], ids[2]]);
+    assert_eq!(search("colour~1 AND NOT colors"), vec![ids[1]]);
+    assert_eq!(search("paralel"), Vec::<u64>::new());
+
+    // Every expanded term adds to the score
+    let hits = index.search("color~1", &SearchOptions::default()).unwrap();
+    assert_eq!(hits[0].id, ids[2]);
+}
+
+#[test]
+fn test_spelling_suggestions() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files = [
+        dir.file("color theory"),
+        dir.file("color wheel"),
+        dir.file("colour"),
+        dir.file("collar"),
+    ];
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+
+    let suggestions = |query: &str| {
+        index
+            .search_page(query, &SearchOptions::default())
+            .unwrap()
+            .suggestions
+    };
+
+    // The closest terms come first, then the ones in the most documents
+    assert_eq!(
+        suggestions("colr AND theory"),
+        BTreeMap::from([(
+            "colr".to_string(),
+            vec![
+                "color".to_string(),
+                "collar".to_string(),
+                "colour".to_string()
+            ]
+        )])
+    );
+    assert!(suggestions("color OR colour*").is_empty());
+    assert!(suggestions("xyzzy").is_empty());
+
+    // Terms of deleted documents are not suggested, their files are removed too
+    index.delete_document(ids[3]).unwrap();
+    assert_eq!(suggestions("colr")["colr"], ["color", "colour"]);
+}
+
+#[test]
+fn test_complete_prefixes() {
+    for mmap in [false, true] {
+        let dir = TestDir::new();
+        let state_file = dir.join("index.bin");
+        let config = IndexConfig {
+            state_file: state_file.clone(),
+            analyzer: AnalyzerKind::Standard,
+            mmap,
+            ..Default::default()
+        };
+        let completion = |term: &str, frequency| (term.to_string(), frequency);
+
+        let updated = dir.file("rust");
+        let ids = {
+            let index = InvertedIndex::with_config(config.clone()).unwrap();
+            let ids: Vec<u64> = [
+                "parallel param",
+                "parallel paris",
+                "parallel paralysis rust",
+            ]
+            .into_iter()
+            .map(|content| index.add_document(dir.file(content)).unwrap())
+            .collect();
+
+            assert_eq!(
+                index.complete("PAR", 2),
+                [completion("parallel", 3), completion("paralysis", 1)]
+            );
+
+            // Frequencies follow updates and deletions
+            index
+                .register_update(ids[0], updated.clone(), Metadata::default())
+                .unwrap();
+            index.index_document(ids[0]);
+            assert_eq!(index.complete("r", 10), [completion("rust", 2)]);
+            assert_eq!(index.complete("param", 10), []);
+
+            index.delete_document(ids[2]).unwrap();
+            assert_eq!(
+                index.complete("par", 10),
+                [completion("parallel", 1), completion("paris", 1)]
+            );
+
+            // Changes after a save count on top of the frequencies saved with it
+            index.save().unwrap();
+            let added = index.add_document(dir.file("parallel")).unwrap();
+            assert_eq!(
+                index.complete("par", 10),
+                [completion("parallel", 2), completion("paris", 1)]
+            );
+            index.delete_document(added).unwrap();
+
+            ids
+        };
+
+        {
+            let index = InvertedIndex::with_config(config.clone()).unwrap();
+            // A mapped index leaves its terms in the dictionary of the state file
+            assert_eq!(index.vocabulary.changes().is_empty(), mmap);
+            assert_eq!(
+                index.complete("", 10),
+                [
+                    completion("parallel", 1),
+                    completion("paris", 1),
+                    completion("rust", 1)
+                ]
+            );
+
+            index.delete_document(ids[1]).unwrap();
+
+            // Simulate a crash, the deletion is replayed from the write-ahead log
+            std::mem::forget(index);
+        }
+
+        let index = InvertedIndex::with_config(config).unwrap();
+        assert_eq!(index.complete("", 10), [completion("rust", 1)]);
+        drop(index);
+    }
+}
+
+#[test]
+fn test_search_snippets() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file = dir.file("Rust threads and Python threads, but NOT java");
+    index.add_document(file.clone()).unwrap();
+
+    let hits = index.search("threads", &SearchOptions::default()).unwrap();
+    assert_eq!(hits[0].snippet, None);
+
+    let options = SearchOptions {
+        snippets: true,
+        ..Default::default()
+    };
+    let hits = index.search("threads AND NOT go", &options).unwrap();
+
+    assert_eq!(
+        hits[0].snippet.as_deref(),
+        Some("Rust <mark>threads</mark> and Python <mark>threads</mark>, but NOT java")
+    );
+
+    // Only the first hits get a snippet, even without a limit
+    for _ in 0..MAX_SNIPPETS {
+        index.add_document(dir.file("threads")).unwrap();
+    }
+    let hits = index.search("threads", &options).unwrap();
+
+    assert_eq!(hits.len(), MAX_SNIPPETS + 1);
+    assert!(hits[..MAX_SNIPPETS].iter().all(|hit| hit.snippet.is_some()));
+    assert_eq!(hits[MAX_SNIPPETS].snippet, None);
+}
+
+#[test]
+fn test_search_ignores_case_and_punctuation() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file = dir.file("=====  Rust  =====\nRUST is fast!");
+    let id = index.add_document(file.clone()).unwrap();
+
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    assert_eq!(search("rust"), vec![id]);
+    assert_eq!(search("Rust"), vec![id]);
+    assert_eq!(search("\"rust is fast\""), vec![id]);
+    assert_eq!(
+        index.search("=====", &SearchOptions::default()),
+        Err(QueryError::NoSearchableTerms)
+    );
+}
+
+#[test]
+fn test_english_analyzer() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let config = IndexConfig {
+        state_file: state_file.clone(),
+        analyzer: AnalyzerKind::English,
+        ..Default::default()
+    };
+    let file = dir.file("The threads are running in a pool");
+
+    {
+        let index = InvertedIndex::with_config(config.clone()).unwrap();
+        let id = index.add_document(file.clone()).unwrap();
+        let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+        assert_eq!(search("thread"), vec![id]);
+        assert_eq!(search("RUNS"), vec![id]);
+        assert_eq!(search("\"threads are running\""), vec![id]);
+        assert_eq!(search("\"threads running\""), Vec::<u64>::new());
+        // Patterns are matched against the stems as they are
+        assert_eq!(search("run*"), vec![id]);
+        assert_eq!(search("running*"), Vec::<u64>::new());
+        assert_eq!(
+            index.search("the", &SearchOptions::default()),
+            Err(QueryError::NoSearchableTerms)
+        );
+    }
+
+    // The analyzer is saved with the index and wins over the configured one
+    {
+        let index = InvertedIndex::with_config(IndexConfig {
+            analyzer: AnalyzerKind::Raw,
+            ..config
+        })
+        .unwrap();
+
+        assert_eq!(index.analyzer.kind(), AnalyzerKind::English);
+        assert_eq!(
+            search_ids(&index, "Pools", &SearchOptions::default()).len(),
+            1
+        );
+    }
+}
+
+#[test]
+fn test_recover_from_write_ahead_log() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let files = [
+        dir.file("saved before the crash"),
+        dir.file("indexed after the save"),
+        dir.file("uploaded but never indexed"),
+        dir.file("deleted after the save"),
+    ];
+
+    let ids: Vec<u64> = {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let saved = index.add_document(files[0].clone()).unwrap();
+        let deleted = index.add_document(files[3].clone()).unwrap();
+        index.save().unwrap();
+
+        let indexed = index.add_document(files[1].clone()).unwrap();
+        let registered = index
+            .register_document(files[2].clone(), Metadata::default())
+            .unwrap();
+        index.delete_document(deleted).unwrap();
+
+        // Simulate a crash: the index is never saved again
+        std::mem::forget(index);
+
+        vec![saved, indexed, registered, deleted]
+    };
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+        assert_eq!(index.get_document_count(), 3);
+        assert_eq!(search("crash"), vec![ids[0]]);
+        assert_eq!(search("indexed"), vec![ids[1], ids[2]]);
+        assert_eq!(search("deleted"), Vec::<u64>::new());
+        assert!(!index.document_exists(ids[3]));
+
+        let file = dir.file("uploaded after recovery");
+        assert_eq!(index.add_document(file.clone()).unwrap(), 4);
+    }
+}
+
+#[test]
+fn test_delete_document_without_file() {
+    for mmap in [false, true] {
+        let dir = TestDir::new();
+        let config = IndexConfig {
+            state_file: dir.join("index.bin"),
+            mmap,
+            ..Default::default()
+        };
+        let suggestions = |index: &InvertedIndex, query: &str| {
+            index
+                .search_page(query, &SearchOptions::default())
+                .unwrap()
+                .suggestions
+        };
+
+        let missing = dir.file("vanished colour");
+        let (kept, removed) = {
+            let index = InvertedIndex::with_config(config.clone()).unwrap();
+            let kept = index.add_document(dir.file("kept color")).unwrap();
+            let removed = index.add_document(missing.clone()).unwrap();
+            index.save().unwrap();
+            (kept, removed)
+        };
+
+        // The terms of a document in the saved state are known without its file
+        let index = InvertedIndex::with_config(config).unwrap();
+        fs::remove_file(&missing).unwrap();
+        index.delete_document(removed).unwrap();
+
+        assert_eq!(index.complete("vanish", 10), []);
+        assert_eq!(index.complete("colo", 10), [("color".to_string(), 1)]);
+        assert_eq!(suggestions(&index, "colr")["colr"], ["color".to_string()]);
+
+        // Just as those of a document indexed since
+        let missing = dir.file("vanished again");
+        let removed = index.add_document(missing.clone()).unwrap();
+        fs::remove_file(&missing).unwrap();
+        index.delete_document(removed).unwrap();
+
+        assert_eq!(
+            index.complete("", 10),
+            [("color".to_string(), 1), ("kept".to_string(), 1)]
+        );
+        assert_eq!(
+            search_ids(&index, "kept", &SearchOptions::default()),
+            [kept]
+        );
+    }
+}
+
+#[test]
+fn test_reconcile_uploads() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let uploads = TestDir::new();
+    let upload = |content: &str| uploads.file(content);
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+    let kept = index.add_document(upload("kept document")).unwrap();
+    let lost_path = upload("lost document");
+    let lost = index.add_document(lost_path.clone()).unwrap();
+    fs::remove_file(lost_path).unwrap();
+
+    // Files of a deleted document are not uploads without a document
+    let deleted = index.add_document(upload("deleted")).unwrap();
+    let deleted_paths = index.unregister_document(deleted).unwrap().unwrap();
+
+    let orphans = [upload("orphan document"), upload("another orphan")];
+
+    // Files still being received are not orphans
+    let receiving = index.start_upload(uploads.join("receiving.txt"));
+    fs::write(receiving.path(), "received document").unwrap();
+
+    let reconciled = index.reconcile_uploads(&uploads.path).unwrap();
+    assert_eq!(reconciled.added.len(), 2);
+    assert!(reconciled.added.iter().all(|&id| id > lost + 1));
+    assert_eq!(reconciled.removed, vec![lost]);
+
+    let added: HashMap<String, u64> = reconciled
+        .added
+        .iter()
+        .map(|&id| (index.get_document_path(id).unwrap(), id))
+        .collect();
+    for &id in added.values() {
+        index.index_document(id);
+    }
+
+    assert_eq!(search("document"), vec![kept, added[&orphans[0]]]);
+    assert_eq!(search("orphan").len(), 2);
+    assert!(!index.document_exists(lost));
+    // The terms of the missing file are gone along with its document
+    assert_eq!(index.complete("lost", 10), []);
+
+    let received = index
+        .register_document(receiving.path().to_string(), Metadata::default())
+        .unwrap();
+    drop(receiving);
+    index.index_document(received);
+    assert_eq!(search("received"), vec![received]);
+
+    index.purge_document(deleted, &deleted_paths).unwrap();
+    assert_eq!(
+        index.reconcile_uploads(&uploads.path).unwrap(),
+        Reconciled::default()
+    );
+}
+
+#[test]
+fn test_update_document() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+    let old_file = dir.file("old content");
+    let new_file = dir.file("new content");
+
+    let metadata = Metadata {
+        filename: Some("new.txt".to_string()),
+        ..Default::default()
+    };
+
+    let id = index.add_document(old_file.clone()).unwrap();
+    assert!(index
+        .register_update(id, new_file.clone(), metadata.clone())
+        .unwrap());
+
+    // The old content is served until the new one has been indexed
+    assert_eq!(search("old"), vec![id]);
+    assert_eq!(search("new"), Vec::<u64>::new());
+    assert_eq!(index.get_document_path(id), Some(old_file.clone()));
+    assert_eq!(index.get_document_metadata(id), Some(Metadata::default()));
+
+    index.index_document(id);
+
+    assert_eq!(index.get_document_metadata(id), Some(metadata));
+
+    assert_eq!(search("old"), Vec::<u64>::new());
+    assert_eq!(search("new"), vec![id]);
+    assert_eq!(search("content"), vec![id]);
+    assert_eq!(index.get_document_path(id), Some(new_file.clone()));
+    assert_eq!(index.get_document_count(), 1);
+    assert!(!Path::new(&old_file).exists());
+
+    let missing = dir.file("missing");
+    assert!(!index
+        .register_update(id + 1, missing.clone(), Metadata::default())
+        .unwrap());
+}
+
+#[test]
+fn test_recover_update_from_write_ahead_log() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let old_file = dir.file("before the update");
+    let new_file = dir.file("after the update");
+
+    let id = {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let id = index.add_document(old_file.clone()).unwrap();
+        index.save().unwrap();
+        assert!(index
+            .register_update(id, new_file.clone(), Metadata::default())
+            .unwrap());
+
+        // Simulate a crash before the update is indexed
+        std::mem::forget(index);
+
+        id
+    };
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let search = |query: &str| search_ids(&index, query, &SearchOptions::default());
+
+        assert_eq!(search("before"), Vec::<u64>::new());
+        assert_eq!(search("after"), vec![id]);
+        assert_eq!(index.get_document_count(), 1);
+        assert!(!Path::new(&old_file).exists());
+
+        index.delete_document(id).unwrap();
+        assert!(!Path::new(&new_file).exists());
+    }
+}
+
+#[test]
+fn test_metadata_survives_restart() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let export_file = dir.join("export.json");
+    let files = [dir.file("saved"), dir.file("logged")];
+    let metadata = |filename: &str| Metadata {
+        filename: Some(filename.to_string()),
+        content_type: Some("text/plain".to_string()),
+        tags: BTreeMap::from([("project".to_string(), "search".to_string())]),
+        uploaded_at: 1_700_000_000,
+        size: 6,
+    };
+
+    let ids = {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        let saved = index
+            .register_document(files[0].clone(), metadata("saved.txt"))
+            .unwrap();
+        index.index_document(saved);
+        index.save().unwrap();
+
+        let logged = index
+            .register_document(files[1].clone(), metadata("logged.txt"))
+            .unwrap();
+
+        // Simulate a crash: the second document is only in the write-ahead log
+        std::mem::forget(index);
+
+        [saved, logged]
+    };
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        assert_eq!(
+            index.get_document_metadata(ids[0]),
+            Some(metadata("saved.txt"))
+        );
+        assert_eq!(
+            index.get_document_metadata(ids[1]),
+            Some(metadata("logged.txt"))
+        );
+        assert_eq!(index.get_document_metadata(2), None);
+
+        index.export_json(&export_file).unwrap();
+    }
+
+    let index = InvertedIndex::with_state_file(export_file.clone());
+    assert_eq!(
+        index.get_document_metadata(ids[1]),
+        Some(metadata("logged.txt"))
+    );
+    drop(index);
+}
+
+#[test]
+fn test_list_documents() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let files: Vec<String> = (0..4).map(|i| dir.file(&format!("document{i}"))).collect();
+    let waiting = dir.file("waiting");
+
+    let ids: Vec<u64> = files
+        .iter()
+        .map(|file| index.add_document(file.clone()).unwrap())
+        .collect();
+    let registered = index
+        .register_document(waiting.clone(), Metadata::default())
+        .unwrap();
+    index.delete_document(ids[1]).unwrap();
+
+    let list = |options: &ListOptions| {
+        let page = index.list_documents(options).unwrap();
+        let ids: Vec<(u64, bool)> = page
+            .documents
+            .iter()
+            .map(|document| (document.id, document.indexed))
+            .collect();
+        (ids, page.next_cursor)
+    };
+
+    let options = ListOptions {
+        limit: Some(2),
+        ..Default::default()
+    };
+    let (first, cursor) = list(&options);
+    assert_eq!(first, [(ids[0], true), (ids[2], true)]);
+
+    let options = ListOptions { cursor, ..options };
+    let (second, cursor) = list(&options);
+    assert_eq!(second, [(ids[3], true), (registered, false)]);
+    assert_eq!(cursor, None);
+
+    let invalid = ListOptions {
+        sort: SortKey::Size,
+        cursor: Some("cursor".to_string()),
+        ..Default::default()
+    };
+    assert!(index.list_documents(&invalid).is_err());
+}
+
+#[test]
+fn test_save_truncates_write_ahead_log() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let file = dir.file("checkpoint");
+    index.add_document(file.clone()).unwrap();
+
+    let log_file = InvertedIndex::log_file(&index.state_file);
+    assert!(fs::metadata(&log_file).unwrap().len() > 0);
+
+    index.save().unwrap();
+    assert_eq!(fs::metadata(&log_file).unwrap().len(), 0);
+}
+
+#[test]
+fn test_load_corrupt_state_file() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let files = [dir.file("saved twice"), dir.file("saved once")];
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+        index.add_document(files[0].clone()).unwrap();
+        index.save().unwrap();
+        index.add_document(files[1].clone()).unwrap();
+    }
+
+    let open = |recovery| {
+        // A save that was cut short by a crash before saves were atomic
+        fs::write(&state_file, r#"{"index": {"saved": {"0": [0]"#).unwrap();
+
+        InvertedIndex::with_config(IndexConfig {
+            state_file: state_file.clone(),
+            recovery,
+            ..Default::default()
+        })
+    };
+
+    assert!(matches!(
+        open(Recovery::Fail),
+        Err(LoadError::InvalidJson(_))
+    ));
+
+    {
+        let index = open(Recovery::Backup).unwrap();
+        assert_eq!(index.get_document_count(), 1);
+        assert_eq!(
+            search_ids(&index, "twice", &SearchOptions::default()),
+            vec![0]
+        );
+    }
+
+    {
+        let index = open(Recovery::Empty).unwrap();
+        assert_eq!(index.get_document_count(), 0);
+    }
+
+    {
+        let index = open(Recovery::Rebuild).unwrap();
+        assert_eq!(index.get_document_count(), 0);
+    }
+
+    let corrupt_file = storage::corrupt_file(&state_file);
+    assert!(fs::read_to_string(&corrupt_file).unwrap().starts_with("{"));
+}
+
+#[test]
+fn test_migrate_json_state_file() {
+    let dir = TestDir::new();
+    let (legacy_state_file, state_file) = (dir.join("index.json"), dir.join("index.bin"));
+    let export_file = dir.join("index.export.json");
+    fs::write(
+        &legacy_state_file,
+        r#"{"index": {"rust": {"0": [0]}}, "documents": {"0": {"path": "doc.txt", "length": 1}}, "last_document_id": 1, "analyzer": "standard"}"#,
+    )
+    .unwrap();
+
+    {
+        let index = InvertedIndex::with_state_file(state_file.clone());
+
+        assert_eq!(
+            search_ids(&index, "rust", &SearchOptions::default()),
+            vec![0]
+        );
+        assert!(fs::read(&state_file).unwrap().starts_with(binary::MAGIC));
+
+        index.export_json(&export_file).unwrap();
+    }
+
+    // The binary file is preferred from now on, the JSON one is only kept around
+    fs::write(&legacy_state_file, "{}").unwrap();
+    assert_eq!(
+        InvertedIndex::with_state_file(state_file.clone()).get_document_count(),
+        1
+    );
+
+    let exported: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&export_file).unwrap()).unwrap();
+    assert_eq!(exported["index"]["rust"]["0"], serde_json::json!([0]));
+    assert_eq!(exported["last_document_id"], 1);
+}
+
+#[test]
+fn test_save_drops_deleted_documents_from_segments() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let kept = dir.file("shared words");
+    let deleted = dir.file("shared unique words");
+    let kept_id = index.add_document(kept.clone()).unwrap();
+    let deleted_id = index.add_document(deleted).unwrap();
+
+    index.delete_document(deleted_id).unwrap();
+    assert!(search_ids(&index, "unique", &SearchOptions::default()).is_empty());
+    assert_eq!(
+        search_ids(&index, "shared", &SearchOptions::default()),
+        vec![kept_id]
+    );
+
+    // Saving merges the segments of every shard into one without the deleted
+    // document
+    index.save().unwrap();
+    assert!(index.shards.iter().all(|shard| shard.segments().len() <= 1));
+    assert!(index.shards[shard::shard_of("unique")]
+        .segments()
+        .iter()
+        .all(|segment| segment.postings("unique").is_none()));
+    {
+        let segments = index.shards[shard::shard_of("shared")].segments();
+        assert_eq!(
+            segments[0]
+                .postings("shared")
+                .unwrap()
+                .documents()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![kept_id]
+        );
+    }
+
+    let reloaded = InvertedIndex::with_state_file(index.state_file.clone());
+    reloaded.delete_document(kept_id).unwrap();
+    reloaded.save().unwrap();
+    assert!(reloaded
+        .shards
+        .iter()
+        .all(|shard| shard.segments().is_empty()));
+}
+
+#[test]
+fn test_merge_segments() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let mut ids = Vec::new();
+    for i in 0..25 {
+        let file = dir.file(&format!("common document{i}"));
+        ids.push(index.add_document(file).unwrap());
+    }
+    index.delete_document(ids[0]).unwrap();
+
+    // One segment per document
+    let shard = &index.shards[shard::shard_of("common")];
+    assert_eq!(shard.segments().len(), 25);
+
+    index.merge_segments();
+    assert!(shard.segments().len() < 25);
+
+    assert_eq!(
+        search_ids(&index, "common", &SearchOptions::default()).len(),
+        24
+    );
+    assert!(search_ids(&index, "document0", &SearchOptions::default()).is_empty());
+    assert_eq!(
+        search_ids(&index, "document24", &SearchOptions::default()),
+        vec![ids[24]]
+    );
+}
+
+#[test]
+fn test_delete_document_while_merging() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let mut ids = Vec::new();
+    for i in 0..segment::MERGE_FACTOR {
+        let file = dir.file(&format!("merged document{i}"));
+        ids.push(index.add_document(file).unwrap());
+    }
+
+    let shard = &index.shards[shard::shard_of("merged")];
+    let selected = shard.segments();
+    let merged = Segment::merge(&selected, |_, _| true);
+
+    // Deleted after liveness was checked, before the merged segment is swapped in
+    index.delete_document(ids[0]).unwrap();
+    shard.replace(&selected, Arc::new(merged));
+
+    assert_eq!(shard.segments().len(), 1);
+    assert_eq!(
+        search_ids(&index, "merged", &SearchOptions::default()),
+        ids[1..].to_vec()
+    );
+    assert!(search_ids(&index, "document0", &SearchOptions::default()).is_empty());
+}
+
+#[test]
+fn test_merge_drops_postings_of_unknown_documents() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.json");
+    // Written by an older version, with postings of a document it does not store
+    fs::write(
+        &state_file,
+        r#"{"index": {"ghost": {"0": [0], "7": [0]}}, "documents": {"0": {"path": "doc.txt", "length": 1}}, "last_document_id": 8, "analyzer": "standard"}"#,
+    )
+    .unwrap();
+
+    let index = InvertedIndex::with_state_file(state_file.clone());
+    let mut ids = vec![0];
+    for _ in 1..segment::MERGE_FACTOR {
+        ids.push(index.add_document(dir.file("ghost")).unwrap());
+    }
+    assert_eq!(search_ids(&index, "ghost", &SearchOptions::default()), ids);
+
+    let shard = &index.shards[shard::shard_of("ghost")];
+    index.merge_segments();
+    assert_eq!(shard.segments().len(), 1);
+
+    assert_eq!(search_ids(&index, "ghost", &SearchOptions::default()), ids);
+}
+
+#[test]
+fn test_forward_index() {
+    for mmap in [false, true] {
+        let dir = TestDir::new();
+        let config = IndexConfig {
+            state_file: dir.join("index.bin"),
+            mmap,
+            ..Default::default()
+        };
+        let terms = |index: &InvertedIndex, id| {
+            let documents = index.documents.read().unwrap();
+            let mut terms = index.document_terms(id, &documents[&id]);
+            terms.sort();
+            terms
+        };
+
+        let (saved, updated) = {
+            let index = InvertedIndex::with_config(config.clone()).unwrap();
+            let saved = index.add_document(dir.file("forward index")).unwrap();
+            let updated = index.add_document(dir.file("old terms")).unwrap();
+            assert_eq!(terms(&index, saved), ["forward", "index"]);
+
+            // Mapped documents are looked up in the state file instead
+            index.save().unwrap();
+            assert_eq!(index.forward_index.read().unwrap().is_empty(), mmap);
+            assert_eq!(terms(&index, saved), ["forward", "index"]);
+
+            index
+                .register_update(updated, dir.file("new terms"), Metadata::default())
+                .unwrap();
+            index.index_document(updated);
+            assert_eq!(terms(&index, updated), ["new", "terms"]);
+
+            (saved, updated)
+        };
+
+        let index = InvertedIndex::with_config(config).unwrap();
+        assert_eq!(terms(&index, saved), ["forward", "index"]);
+        assert_eq!(terms(&index, updated), ["new", "terms"]);
+
+        index.delete_document(saved).unwrap();
+        assert!(!index.forward_index.read().unwrap().contains_key(&saved));
+    }
+}
+
+#[test]
+fn test_concurrent_indexing_across_shards() {
+    let dir = TestDir::new();
+    let index = dir.index();
+    let ids: Vec<u64> = (0..40)
+        .map(|i| {
+            let file = dir.file(&format!("parallel indexing of document{i}"));
+            index.register_document(file, Metadata::default()).unwrap()
+        })
+        .collect();
+
+    let index = &index;
+    std::thread::scope(|scope| {
+        for chunk in ids.chunks(5) {
+            scope.spawn(move || {
+                for &id in chunk {
+                    index.index_document(id);
+                    index.merge_segments();
+                }
+            });
+        }
+    });
+
+    let options = SearchOptions::default();
+    assert_eq!(search_ids(index, "\"parallel indexing\"", &options), ids);
+    for (i, id) in ids.iter().enumerate() {
+        assert_eq!(
+            search_ids(index, &format!("document{i}"), &options),
+            vec![*id]
+        );
+    }
+}
+
+#[test]
+fn test_memory_mapped_index() {
+    let dir = TestDir::new();
+    let state_file = dir.join("index.bin");
+    let config = IndexConfig {
+        state_file: state_file.clone(),
+        mmap: true,
+        ..Default::default()
+    };
+    let options = SearchOptions::default();
+
+    let saved = dir.file("mapped postings on disk");
+    let deleted = dir.file("mapped but deleted");
+    let (saved_id, deleted_id) = {
+        let index = InvertedIndex::with_config(config.clone()).unwrap();
+        let saved_id = index.add_document(saved.clone()).unwrap();
+        let deleted_id = index.add_document(deleted).unwrap();
+
+        // Once saved, the documents are only found through the mapping
+        index.save().unwrap();
+        assert!(index.base.read().unwrap().is_some());
+        assert!(index.shards.iter().all(|shard| shard.segments().is_empty()));
+        assert_eq!(
+            search_ids(&index, "mapped", &options),
+            vec![saved_id, deleted_id]
+        );
+
+        (saved_id, deleted_id)
+    };
+
+    let index = InvertedIndex::with_config(config).unwrap();
+    assert!(index.shards.iter().all(|shard| shard.segments().is_empty()));
+    assert_eq!(
+        search_ids(&index, "\"postings on disk\"", &options),
+        vec![saved_id]
+    );
+    assert_eq!(search_ids(&index, "post*", &options), vec![saved_id]);
+
+    // New documents are indexed into segments on top of the mapping
+    let added = dir.file("mapped overlay");
+    let added_id = index.add_document(added.clone()).unwrap();
+    index.delete_document(deleted_id).unwrap();
+    assert_eq!(
+        search_ids(&index, "mapped", &options),
+        vec![saved_id, added_id]
+    );
+
+    // A replaced version in the mapping no longer matches
+    let updated = dir.file("mapped postings in memory");
+    index
+        .register_update(saved_id, updated, Metadata::default())
+        .unwrap();
+    index.index_document(saved_id);
+    assert!(search_ids(&index, "disk", &options).is_empty());
+    assert_eq!(search_ids(&index, "memory", &options), vec![saved_id]);
+    assert_eq!(
+        search_ids(&index, "\"postings in memory\"", &options),
+        vec![saved_id]
+    );
+
+    index.save().unwrap();
+    drop(index);
+
+    // Without mmap the same file is loaded onto the heap
+    let index = InvertedIndex::with_state_file(state_file.clone());
+    assert_eq!(
+        search_ids(&index, "mapped", &options),
+        vec![saved_id, added_id]
+    );
+    assert_eq!(search_ids(&index, "/over.*/", &options), vec![added_id]);
+    assert!(search_ids(&index, "disk", &options).is_empty());
+    drop(index);
 }
@@ -1,16 +1,25 @@
 use regex::Regex;
 
-pub fn tokenize(text: &str) -> Vec<String> {
-    let re = Regex::new(r"[\w'-]+|[[:punct:]]+").unwrap();
-    re.find_iter(text)
-        .map(|mat| mat.as_str().to_string())
-        .collect()
+lazy_static::lazy_static! {
+    static ref TOKEN: Regex = Regex::new(r"[\w'-]+|[[:punct:]]+").unwrap();
+}
+
+/// Splits text into words and punctuation runs, yielding the byte offset at which
+/// every token starts.
+pub fn tokenize_with_offsets(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    TOKEN.find_iter(text).map(|mat| (mat.start(), mat.as_str()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tokenize(text: &str) -> Vec<&str> {
+        tokenize_with_offsets(text)
+            .map(|(_, token)| token)
+            .collect()
+    }
+
     #[test]
     fn test_simple_sentence() {
         let text = "It's such a beautiful day!!";
@@ -66,6 +75,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_offsets() {
+        let text = "Hello,  world!";
+        let tokens: Vec<_> = tokenize_with_offsets(text).collect();
+        assert_eq!(
+            tokens,
+            vec![(0, "Hello"), (5, ","), (8, "world"), (13, "!")]
+        );
+    }
+
     #[test]
     fn test_very_special_title() {
         let text = "=====================  Product Identification  =====================";
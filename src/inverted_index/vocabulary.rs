@@ -0,0 +1,359 @@
+use super::mapped::MappedIndex;
+use super::query::Pattern;
+use levenshtein_automata::{Distance, DFA, SINK_STATE};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// Every term of the index in sorted order with the number of documents containing
+/// it, so that the terms matching a pattern or starting with a prefix can be found
+/// without hashing every possible term.
+///
+/// The terms of a mapped state file are read from its dictionary as they are
+/// needed, only the changes since it was saved are kept on the heap.
+#[derive(Debug, Default)]
+pub struct Vocabulary {
+    terms: RwLock<Terms>,
+}
+
+#[derive(Debug, Default)]
+struct Terms {
+    // Dictionary of the mapped state file, with the document frequencies at the time
+    // it was saved
+    base: Option<Arc<MappedIndex>>,
+    // Term -> Change of the document frequency since the base was saved, or the whole
+    // document frequency without a base
+    changes: BTreeMap<String, i64>,
+}
+
+impl Terms {
+    /// Terms from `start` on in sorted order, with their document frequencies.
+    fn range(&self, start: Bound<&str>) -> impl Iterator<Item = (&str, usize)> {
+        let mut base = self
+            .base
+            .as_deref()
+            .map(|base| base.document_frequencies(start))
+            .into_iter()
+            .flatten()
+            .peekable();
+        let mut changes = self
+            .changes
+            .range::<str, _>((start, Bound::Unbounded))
+            .peekable();
+
+        std::iter::from_fn(move || loop {
+            let order = match (base.peek(), changes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((base_term, _)), Some((term, _))) => (*base_term).cmp(term.as_str()),
+            };
+
+            let (term, frequency) = match order {
+                Ordering::Less => base
+                    .next()
+                    .map(|(term, frequency)| (term, frequency as i64))
+                    .unwrap(),
+                Ordering::Greater => changes
+                    .next()
+                    .map(|(term, change)| (term.as_str(), *change))
+                    .unwrap(),
+                Ordering::Equal => {
+                    let (term, frequency) = base.next().unwrap();
+                    let (_, change) = changes.next().unwrap();
+                    (term, frequency as i64 + change)
+                }
+            };
+
+            if frequency > 0 {
+                return Some((term, frequency as usize));
+            }
+        })
+    }
+}
+
+impl Vocabulary {
+    /// Terms of `base` and `terms` with their document frequencies, which add up for
+    /// terms in both.
+    pub fn new(base: Option<Arc<MappedIndex>>, terms: BTreeMap<String, usize>) -> Self {
+        let changes = terms
+            .into_iter()
+            .map(|(term, frequency)| (term, frequency as i64))
+            .collect();
+
+        Vocabulary {
+            terms: RwLock::new(Terms { base, changes }),
+        }
+    }
+
+    /// Counts the distinct `terms` of a document that was indexed.
+    pub fn add<'a>(&self, terms: impl IntoIterator<Item = &'a str>) {
+        self.change(terms, 1);
+    }
+
+    /// Stops counting the distinct `terms` of a document that was deleted or
+    /// replaced. Terms no other document contains are dropped.
+    pub fn remove<'a>(&self, terms: impl IntoIterator<Item = &'a str>) {
+        self.change(terms, -1);
+    }
+
+    fn change<'a>(&self, terms: impl IntoIterator<Item = &'a str>, change: i64) {
+        let mut known = self.terms.write().unwrap();
+
+        for term in terms {
+            match known.changes.get_mut(term) {
+                Some(frequency) => {
+                    *frequency += change;
+                    if *frequency == 0 {
+                        known.changes.remove(term);
+                    }
+                }
+                None => {
+                    known.changes.insert(term.to_string(), change);
+                }
+            }
+        }
+    }
+
+    /// A copy of the changes since the base was saved, to be passed to
+    /// [`Vocabulary::rebase`] once the index they were taken with has been saved.
+    pub fn changes(&self) -> BTreeMap<String, i64> {
+        self.terms.read().unwrap().changes.clone()
+    }
+
+    /// Replaces the base with a newly saved state file, which holds the `saved`
+    /// changes. Only the changes made since are kept.
+    pub fn rebase(&self, base: Arc<MappedIndex>, saved: BTreeMap<String, i64>) {
+        let mut terms = self.terms.write().unwrap();
+        terms.base = Some(base);
+
+        for (term, saved) in saved {
+            let change = terms.changes.entry(term).or_default();
+            *change -= saved;
+        }
+        terms.changes.retain(|_, change| *change != 0);
+    }
+
+    /// Number of documents containing `term`.
+    pub fn document_frequency(&self, term: &str) -> usize {
+        self.terms
+            .read()
+            .unwrap()
+            .range(Bound::Included(term))
+            .next()
+            .filter(|(candidate, _)| *candidate == term)
+            .map_or(0, |(_, frequency)| frequency)
+    }
+
+    /// The `limit` terms starting with `prefix` that the most documents contain, with
+    /// their document frequencies. Equally frequent terms are in sorted order.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let terms = self.terms.read().unwrap();
+
+        let mut completions: Vec<(&str, usize)> = terms
+            .range(Bound::Included(prefix))
+            .take_while(|(term, _)| term.starts_with(prefix))
+            .collect();
+
+        let order = |a: &(&str, usize), b: &(&str, usize)| b.1.cmp(&a.1).then(a.0.cmp(b.0));
+        if completions.len() > limit {
+            completions.select_nth_unstable_by(limit, order);
+            completions.truncate(limit);
+        }
+        completions.sort_by(order);
+
+        completions
+            .into_iter()
+            .map(|(term, frequency)| (term.to_string(), frequency))
+            .collect()
+    }
+
+    /// The first `limit` terms matching `pattern`, in sorted order.
+    pub fn matching(&self, pattern: &Pattern, limit: usize) -> Vec<String> {
+        let terms = self.terms.read().unwrap();
+
+        if let Some(automaton) = pattern.automaton() {
+            return accepted(&terms, automaton, limit);
+        }
+
+        terms
+            .range(Bound::Included(pattern.prefix()))
+            .map(|(term, _)| term)
+            .take_while(|term| term.starts_with(pattern.prefix()))
+            .filter(|term| pattern.matches(term))
+            .take(limit)
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// The first `limit` of `terms` accepted by `automaton`, in sorted order.
+///
+/// Every term is only run through the automaton after the prefix it shares with the
+/// previous term, and once a prefix is rejected all terms starting with it are skipped.
+fn accepted(terms: &Terms, automaton: &DFA, limit: usize) -> Vec<String> {
+    let mut accepted = Vec::new();
+    // States after every byte of the previous term, as far as it was run
+    let mut states = vec![automaton.initial_state()];
+    let mut previous = "";
+
+    let mut candidates = terms.range(Bound::Unbounded);
+    while let Some((term, _)) = candidates.next() {
+        if accepted.len() == limit {
+            break;
+        }
+
+        let shared = previous
+            .bytes()
+            .zip(term.bytes())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(states.len() - 1);
+        states.truncate(shared + 1);
+        previous = term;
+
+        let rejected = term.bytes().skip(shared).position(|byte| {
+            let state = automaton.transition(*states.last().unwrap(), byte);
+            states.push(state);
+            state == SINK_STATE
+        });
+
+        match rejected {
+            Some(position) => {
+                // All terms starting with the rejected prefix come before the prefix
+                // followed by the last character
+                let end = (shared + position + 1..=term.len())
+                    .find(|&end| term.is_char_boundary(end))
+                    .unwrap();
+                let skip = format!("{}{}", &term[..end], char::MAX);
+
+                candidates = terms.range(Bound::Excluded(skip.as_str()));
+            }
+            None => {
+                if let Distance::Exact(_) = automaton.distance(*states.last().unwrap()) {
+                    accepted.push(term.to_string());
+                }
+            }
+        }
+    }
+
+    accepted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Operator, Query};
+    use super::*;
+
+    fn pattern(text: &str) -> Pattern {
+        match Query::parse(text, Operator::Or, &Default::default()) {
+            Ok(Query::Pattern(pattern)) => pattern,
+            query => panic!("{text} is not a pattern: {query:?}"),
+        }
+    }
+
+    #[test]
+    fn test_matching() {
+        let vocabulary = Vocabulary::default();
+        vocabulary.add(["parallel", "paralysis", "color", "colour", "colours"]);
+        vocabulary.add(["parallel", "param"]);
+
+        assert_eq!(
+            vocabulary.matching(&pattern("para*"), 10),
+            ["parallel", "paralysis", "param"]
+        );
+        assert_eq!(vocabulary.matching(&pattern("paral*"), 1), ["parallel"]);
+        assert_eq!(vocabulary.matching(&pattern("colo?r"), 10), ["colour"]);
+        assert_eq!(
+            vocabulary.matching(&pattern("colo*r"), 10),
+            ["color", "colour"]
+        );
+        assert_eq!(
+            vocabulary.matching(&pattern("/col(o|ou)rs?/"), 10),
+            ["color", "colour", "colours"]
+        );
+        assert_eq!(
+            vocabulary.matching(&pattern("/a.*/"), 10),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_matching() {
+        let vocabulary = Vocabulary::default();
+        vocabulary.add([
+            "color", "colour", "colours", "collar", "cool", "parallel", "paralel", "über", "uber",
+            "zebra",
+        ]);
+
+        assert_eq!(
+            vocabulary.matching(&pattern("colour~1"), 10),
+            ["color", "colour", "colours"]
+        );
+        assert_eq!(
+            vocabulary.matching(&pattern("colour~2"), 10),
+            ["collar", "color", "colour", "colours"]
+        );
+        assert_eq!(
+            vocabulary.matching(&pattern("colour~2"), 2),
+            ["collar", "color"]
+        );
+        assert_eq!(
+            vocabulary.matching(&pattern("prallel~"), 10),
+            ["paralel", "parallel"]
+        );
+        assert_eq!(vocabulary.matching(&pattern("ubre~1"), 10), ["uber"]);
+        assert_eq!(
+            vocabulary.matching(&pattern("uber~1"), 10),
+            ["uber", "über"]
+        );
+
+        // Skipping rejected prefixes finds the same terms as checking every term
+        for query in ["cloor~2", "zbera~2", "a~1", "über~2", "paralell~1"] {
+            let pattern = pattern(query);
+            let expected: Vec<String> = vocabulary
+                .changes()
+                .keys()
+                .filter(|term| pattern.matches(term))
+                .cloned()
+                .collect();
+            assert_eq!(vocabulary.matching(&pattern, 10), expected);
+        }
+    }
+
+    #[test]
+    fn test_complete() {
+        let vocabulary = Vocabulary::default();
+        vocabulary.add(["parallel", "param", "rust"]);
+        vocabulary.add(["parallel", "paris", "param"]);
+        vocabulary.add(["parallel", "paralysis"]);
+
+        let complete =
+            |prefix, limit| -> Vec<(String, usize)> { vocabulary.complete(prefix, limit) };
+        let completion = |term: &str, frequency| (term.to_string(), frequency);
+
+        assert_eq!(
+            complete("par", 3),
+            [
+                completion("parallel", 3),
+                completion("param", 2),
+                completion("paralysis", 1)
+            ]
+        );
+        assert_eq!(complete("rust", 10), [completion("rust", 1)]);
+        assert_eq!(complete("zig", 10), []);
+
+        // Terms are dropped once no document contains them
+        vocabulary.remove(["parallel", "param", "rust"]);
+        assert_eq!(
+            complete("", 10),
+            [
+                completion("parallel", 2),
+                completion("paralysis", 1),
+                completion("param", 1),
+                completion("paris", 1)
+            ]
+        );
+    }
+}
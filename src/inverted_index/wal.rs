@@ -0,0 +1,192 @@
+use super::Metadata;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+/// A change to the set of documents, logged before it is acknowledged to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    Add {
+        id: u64,
+        path: String,
+        metadata: Metadata,
+    },
+    Update {
+        id: u64,
+        path: String,
+        metadata: Metadata,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+impl Operation {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Operation::Add { id, path, metadata } => serde_json::json!({
+                "op": "add",
+                "id": id,
+                "path": path,
+                "metadata": metadata.to_json(),
+            }),
+            Operation::Update { id, path, metadata } => serde_json::json!({
+                "op": "update",
+                "id": id,
+                "path": path,
+                "metadata": metadata.to_json(),
+            }),
+            Operation::Delete { id } => serde_json::json!({
+                "op": "delete",
+                "id": id,
+            }),
+        }
+    }
+
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let id = value["id"].as_u64()?;
+
+        // Logs written before metadata existed have none
+        let metadata = || match value.get("metadata") {
+            Some(metadata) => Metadata::from_json(metadata),
+            None => Some(Metadata::default()),
+        };
+
+        match value["op"].as_str()? {
+            "add" => Some(Operation::Add {
+                id,
+                path: value["path"].as_str()?.to_string(),
+                metadata: metadata()?,
+            }),
+            "update" => Some(Operation::Update {
+                id,
+                path: value["path"].as_str()?.to_string(),
+                metadata: metadata()?,
+            }),
+            "delete" => Some(Operation::Delete { id }),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only log of operations applied since the last checkpoint, one JSON
+/// object per line.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(WriteAheadLog { file })
+    }
+
+    /// Reads every complete operation from the log at `path`. A missing log is empty.
+    pub fn read(path: &str) -> std::io::Result<Vec<Operation>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut operations = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+
+            // The last line is cut short if the server died while appending it. That
+            // operation was never acknowledged, so it is safe to drop.
+            match serde_json::from_str(&line)
+                .ok()
+                .as_ref()
+                .and_then(Operation::from_json)
+            {
+                Some(operation) => operations.push(operation),
+                None => {
+                    warn!("Skipping malformed write-ahead log entry: {line:?}");
+                    break;
+                }
+            }
+        }
+
+        Ok(operations)
+    }
+
+    /// Writes the operation and waits until it reaches the disk.
+    pub fn append(&mut self, operation: &Operation) -> std::io::Result<()> {
+        let mut line = operation.to_json().to_string();
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    /// Discards all logged operations once they are part of a saved index.
+    pub fn truncate(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn test_append_read_and_truncate() {
+        let dir = TestDir::new();
+        let path = dir.join("index.wal");
+        let operations = vec![
+            Operation::Add {
+                id: 0,
+                path: "uploads/a.txt".to_string(),
+                metadata: Metadata::default(),
+            },
+            Operation::Update {
+                id: 0,
+                path: "uploads/b.txt".to_string(),
+                metadata: Metadata {
+                    filename: Some("b.txt".to_string()),
+                    size: 3,
+                    ..Default::default()
+                },
+            },
+            Operation::Delete { id: 0 },
+        ];
+
+        let mut log = WriteAheadLog::open(&path).unwrap();
+        for operation in &operations {
+            log.append(operation).unwrap();
+        }
+        assert_eq!(WriteAheadLog::read(&path).unwrap(), operations);
+
+        log.truncate().unwrap();
+        log.append(&operations[2]).unwrap();
+        assert_eq!(
+            WriteAheadLog::read(&path).unwrap(),
+            vec![Operation::Delete { id: 0 }]
+        );
+    }
+
+    #[test]
+    fn test_torn_entry_is_skipped() {
+        let dir = TestDir::new();
+        let path = dir.join("index.wal");
+        std::fs::write(
+            &path,
+            "{\"op\":\"delete\",\"id\":3}\n{\"op\":\"add\",\"id\":4,\"pa",
+        )
+        .unwrap();
+
+        assert_eq!(
+            WriteAheadLog::read(&path).unwrap(),
+            vec![Operation::Delete { id: 3 }]
+        );
+        assert!(WriteAheadLog::read(&dir.join("missing.wal"))
+            .unwrap()
+            .is_empty());
+    }
+}
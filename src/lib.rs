@@ -5,4 +5,4 @@ pub mod scheduler;
 pub mod threadpool;
 
 pub const UPLOADS_DIR: &str = "uploads";
-pub const STATE_FILE: &str = "index.json";
+pub const STATE_FILE: &str = "index.bin";
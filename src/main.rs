@@ -1,47 +1,171 @@
-use course_work_parallel_computing::scheduler::Scheduler;
+use clap::Parser;
+use course_work_parallel_computing::inverted_index::{AnalyzerKind, IndexConfig, Recovery};
+use course_work_parallel_computing::scheduler::{Scheduler, Task};
 use course_work_parallel_computing::{
-    handler::Handler, inverted_index::InvertedIndex, threadpool::ThreadPool, UPLOADS_DIR,
+    handler::{self, Handler},
+    inverted_index::InvertedIndex,
+    UPLOADS_DIR,
 };
-use env_logger;
-use log::{error, info};
+use log::{error, info, warn};
 use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-const HANDLER_THREAD_POOL_SIZE: usize = 10;
 const SCHEDULER_THREAD_POOL_SIZE: usize = 10;
+// Connections served at once, each by a thread of its own. Further ones are answered
+// with an error and closed.
+const MAX_CONNECTIONS: usize = 100;
+// How often the index is saved, which also empties the write-ahead log
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+#[command(version, about = "Inverted index search server", long_about = None)]
+struct Cli {
+    /// Text analyzer for a new index: raw, standard or english.
+    /// An existing index keeps the analyzer it was built with.
+    #[arg(long, default_value = "standard")]
+    analyzer: AnalyzerKind,
+
+    /// What to do if the saved index cannot be loaded: fail, backup (restore the
+    /// state before the last save), empty (start over) or rebuild (start over and
+    /// re-index the uploaded files, as with --rebuild).
+    #[arg(long, default_value = "fail")]
+    on_corrupt_index: Recovery,
+
+    /// Write the index to this file as JSON and exit instead of serving requests.
+    #[arg(long, value_name = "PATH")]
+    export_json: Option<String>,
+
+    /// Map the saved index into memory, so that postings are read from disk on
+    /// demand instead of being loaded up front.
+    #[arg(long)]
+    mmap: bool,
+
+    /// Register the files in the uploads directory that have no document and index
+    /// them before serving requests, e.g. after the state file was lost. Documents
+    /// whose files are gone are deleted.
+    #[arg(long)]
+    rebuild: bool,
+}
+
+/// Counts an open connection until dropped, also when its handler panics.
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 fn main() {
+    let cli = Cli::parse();
+
     env_logger::init();
+
+    let inverted_index = match InvertedIndex::with_config(IndexConfig {
+        analyzer: cli.analyzer,
+        recovery: cli.on_corrupt_index,
+        mmap: cli.mmap,
+        ..Default::default()
+    }) {
+        Ok(inverted_index) => Arc::new(inverted_index),
+        Err(e) => {
+            error!("Failed to load index: {e}");
+            error!(
+                "Restart with --on-corrupt-index backup, --on-corrupt-index empty or --on-corrupt-index rebuild"
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(path) = cli.export_json {
+        match inverted_index.export_json(&path) {
+            Ok(()) => info!("Index exported to {path}"),
+            Err(e) => {
+                error!("Failed to export index: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let listener = TcpListener::bind("127.0.0.1:7878").expect("Could not bind to address");
 
     std::fs::create_dir_all(UPLOADS_DIR).expect("Failed to create uploads directory");
 
-    info!("Server listening on 127.0.0.1:7878");
+    let scheduler = Arc::new(Scheduler::new(
+        SCHEDULER_THREAD_POOL_SIZE,
+        Arc::clone(&inverted_index),
+    ));
+
+    if cli.rebuild || cli.on_corrupt_index == Recovery::Rebuild {
+        info!("Rebuilding index from {UPLOADS_DIR}");
+
+        match inverted_index.reconcile_uploads(UPLOADS_DIR) {
+            Ok(reconciled) => {
+                let count = reconciled.added.len();
+                scheduler.run_all_and_wait(
+                    reconciled.added.into_iter().map(Task::AddDocument).collect(),
+                );
+                info!(
+                    "Indexed {count} files without a document, deleted {} documents without files",
+                    reconciled.removed.len()
+                );
+
+                if let Err(e) = inverted_index.save() {
+                    error!("Failed to save index: {e}");
+                }
+            }
+            Err(e) => {
+                error!("Failed to rebuild index: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let inverted_index = Arc::new(InvertedIndex::new());
+    info!("Server listening on 127.0.0.1:7878");
 
     let inverted_index_save_handle = Arc::clone(&inverted_index);
     ctrlc::set_handler(move || {
-        inverted_index_save_handle.save();
+        if let Err(e) = inverted_index_save_handle.save() {
+            error!("Failed to save index: {e}");
+        }
         std::process::exit(0);
     }).expect("Failed to set Ctrl-C handler");
 
-    let handler_thread_pool = ThreadPool::new(HANDLER_THREAD_POOL_SIZE);
+    let inverted_index_checkpoint_handle = Arc::clone(&inverted_index);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(CHECKPOINT_INTERVAL);
+        if let Err(e) = inverted_index_checkpoint_handle.save() {
+            error!("Failed to save index: {e}");
+        }
+    });
 
-    let scheduler = Arc::new(Scheduler::new(
-        SCHEDULER_THREAD_POOL_SIZE,
-        Arc::clone(&inverted_index),
-    ));
+    let open_connections = Arc::new(AtomicUsize::new(0));
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                if open_connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    open_connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("Rejecting connection, {MAX_CONNECTIONS} connections are open");
+                    handler::reject_connection(stream);
+                    continue;
+                }
+                let connection = OpenConnection(Arc::clone(&open_connections));
+
                 info!("New connection established");
 
                 let mut handler =
                     Handler::new(stream, Arc::clone(&inverted_index), Arc::clone(&scheduler));
 
-                handler_thread_pool.execute(move || handler.handle_client());
+                // Connections stay open between commands, so each one gets its
+                // own thread rather than holding on to a worker of a fixed pool.
+                std::thread::spawn(move || {
+                    let _connection = connection;
+                    handler.handle_client();
+                });
             }
             Err(e) => {
                 error!("Error accepting connection: {e:#?}");
@@ -1,11 +1,28 @@
+use crate::channel::Channel;
 use crate::inverted_index::InvertedIndex;
 use crate::threadpool::ThreadPool;
 use log::debug;
 use std::sync::Arc;
 
 pub enum Task {
-    AddDocument(String),
-    DeleteDocument(u64),
+    AddDocument(u64),
+    /// Indexes the new content of a document registered with
+    /// [`InvertedIndex::register_update`] and swaps it in for the old one
+    UpdateDocument(u64),
+    /// Removes the files of a document that has already been unregistered
+    PurgeDocument(u64, Vec<String>),
+}
+
+/// Signals a waiting caller once dropped, so that a task that panics still
+/// releases [`Scheduler::run_and_wait`] instead of blocking it forever.
+struct Done(Option<Arc<Channel<()>>>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        if let Some(done) = self.0.take() {
+            done.send(());
+        }
+    }
 }
 
 pub struct Scheduler {
@@ -22,17 +39,49 @@ impl Scheduler {
             thread_pool,
         }
     }
+
     pub fn run(&self, task: Task) {
+        self.execute(task, None);
+    }
+
+    /// Runs the task on the thread pool and blocks until it has been executed.
+    pub fn run_and_wait(&self, task: Task) {
+        let done = Arc::new(Channel::new());
+
+        self.execute(task, Some(Arc::clone(&done)));
+
+        done.receive();
+    }
+
+    /// Runs all tasks on the thread pool, in parallel as far as there are threads, and
+    /// blocks until every one of them has been executed.
+    pub fn run_all_and_wait(&self, tasks: Vec<Task>) {
+        let done = Arc::new(Channel::new());
+        let count = tasks.len();
+
+        for task in tasks {
+            self.execute(task, Some(Arc::clone(&done)));
+        }
+
+        for _ in 0..count {
+            done.receive();
+        }
+    }
+
+    fn execute(&self, task: Task, done: Option<Arc<Channel<()>>>) {
         let inverted_index = Arc::clone(&self.inverted_index);
+        let done = Done(done);
         self.thread_pool.execute(move || {
             let start = std::time::Instant::now();
 
+            let indexed = matches!(task, Task::AddDocument(_) | Task::UpdateDocument(_));
+
             match task {
-                Task::AddDocument(document) => {
-                    inverted_index.add_document(document.clone());
+                Task::AddDocument(document_id) | Task::UpdateDocument(document_id) => {
+                    inverted_index.index_document(document_id);
                 }
-                Task::DeleteDocument(document_id) => {
-                    if let Err(e) = inverted_index.delete_document(document_id) {
+                Task::PurgeDocument(document_id, paths) => {
+                    if let Err(e) = inverted_index.purge_document(document_id, &paths) {
                         log::error!("Failed to delete document: {e}");
                     }
                 }
@@ -40,6 +89,13 @@ impl Scheduler {
 
             let elapsed = start.elapsed();
             debug!("Job executed in {elapsed:?}");
+
+            drop(done);
+
+            // Merging happens after the client has been answered
+            if indexed {
+                inverted_index.merge_segments();
+            }
         });
     }
 }
@@ -40,7 +40,9 @@ impl ThreadPool {
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.channel.take());
+        if let Some(channel) = self.channel.take() {
+            channel.close();
+        }
 
         for worker in &mut self.workers {
             info!("Shutting down worker {}", worker.id);